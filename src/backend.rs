@@ -0,0 +1,403 @@
+//! A trait for the providers of completions, so that the code that uses them can be tested.
+//!
+//! [`crate::DavinciClient`] implements [`CompletionBackend`]. Code that takes a
+//! `&dyn CompletionBackend`, or is generic over it, can be given a [`MockBackend`] in its tests,
+//! or any other provider that implements the trait.
+//!
+//! ```
+//! use davinci::{CompletionBackend, CompletionRequest, DavinciError, MockBackend, MockRequest};
+//!
+//! async fn greet(backend: &dyn CompletionBackend, name: &str) -> Result<String, DavinciError> {
+//!     let request = CompletionRequest::new(format!("Write a greeting for {}", name));
+//!     backend.complete(&request).await
+//! }
+//!
+//! # tokio::runtime::Runtime::new().unwrap().block_on(async {
+//! let backend = MockBackend::new();
+//! backend.push_completion("Hello, Ada!").push_error(DavinciError::EmptyChoices);
+//!
+//! assert_eq!(greet(&backend, "Ada").await.unwrap(), "Hello, Ada!");
+//! assert!(matches!(greet(&backend, "Bob").await, Err(DavinciError::EmptyChoices)));
+//!
+//! let requests = backend.requests();
+//! assert!(matches!(&requests[1], MockRequest::Completion(request)
+//!     if request.prompt == "Write a greeting for Bob".into()));
+//! # });
+//! ```
+use crate::sync::lock;
+use crate::{
+    ChatChoice, ChatChunkChoice, ChatCompletionChunk, ChatCompletionRequest,
+    ChatCompletionResponse, ChatCompletionStream, ChatDelta, ChatMessage, Choice, CompletionChunk,
+    CompletionRequest, CompletionResponse, CompletionStream, DavinciClient, DavinciError,
+    FinishReason, Role, StreamOptions,
+};
+use futures::future::BoxFuture;
+use futures::stream;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// A provider of completions and chat completions.
+///
+/// The methods return boxed futures, so that the trait can be used as `dyn CompletionBackend`.
+pub trait CompletionBackend: Send + Sync {
+    /// Sends a completion request and returns the whole response.
+    fn create_completion<'a>(
+        &'a self,
+        request: &'a CompletionRequest,
+    ) -> BoxFuture<'a, Result<CompletionResponse, DavinciError>>;
+
+    /// Sends a completion request and returns the chunks of the answer as they arrive.
+    fn stream_completion<'a>(
+        &'a self,
+        request: &'a CompletionRequest,
+    ) -> BoxFuture<'a, Result<CompletionStream, DavinciError>>;
+
+    /// Sends a chat completion request and returns the whole response.
+    fn create_chat_completion<'a>(
+        &'a self,
+        request: &'a ChatCompletionRequest,
+    ) -> BoxFuture<'a, Result<ChatCompletionResponse, DavinciError>>;
+
+    /// Sends a chat completion request and returns the chunks of the answer as they arrive.
+    fn stream_chat<'a>(
+        &'a self,
+        request: &'a ChatCompletionRequest,
+    ) -> BoxFuture<'a, Result<ChatCompletionStream, DavinciError>>;
+
+    /// Sends a completion request and returns the text of the first choice.
+    fn complete<'a>(
+        &'a self,
+        request: &'a CompletionRequest,
+    ) -> BoxFuture<'a, Result<String, DavinciError>> {
+        Box::pin(async move { self.create_completion(request).await?.into_text() })
+    }
+
+    /// Sends a chat completion request and returns the text of the first answer.
+    fn chat<'a>(
+        &'a self,
+        request: &'a ChatCompletionRequest,
+    ) -> BoxFuture<'a, Result<String, DavinciError>> {
+        Box::pin(async move { self.create_chat_completion(request).await?.into_text() })
+    }
+}
+
+impl CompletionBackend for DavinciClient {
+    fn create_completion<'a>(
+        &'a self,
+        request: &'a CompletionRequest,
+    ) -> BoxFuture<'a, Result<CompletionResponse, DavinciError>> {
+        Box::pin(DavinciClient::create_completion(self, request))
+    }
+
+    fn stream_completion<'a>(
+        &'a self,
+        request: &'a CompletionRequest,
+    ) -> BoxFuture<'a, Result<CompletionStream, DavinciError>> {
+        Box::pin(DavinciClient::stream_completion(self, request))
+    }
+
+    fn create_chat_completion<'a>(
+        &'a self,
+        request: &'a ChatCompletionRequest,
+    ) -> BoxFuture<'a, Result<ChatCompletionResponse, DavinciError>> {
+        Box::pin(DavinciClient::create_chat_completion(self, request))
+    }
+
+    fn stream_chat<'a>(
+        &'a self,
+        request: &'a ChatCompletionRequest,
+    ) -> BoxFuture<'a, Result<ChatCompletionStream, DavinciError>> {
+        Box::pin(DavinciClient::stream_chat(self, request))
+    }
+}
+
+/// A scripted answer of a [`MockBackend`].
+#[derive(Debug)]
+pub enum MockReply {
+    /// The response of a completion request.
+    Completion(CompletionResponse),
+    /// The chunks, or errors, of a streamed completion request.
+    CompletionStream(Vec<Result<CompletionChunk, DavinciError>>),
+    /// The response of a chat completion request.
+    Chat(ChatCompletionResponse),
+    /// The chunks, or errors, of a streamed chat completion request.
+    ChatStream(Vec<Result<ChatCompletionChunk, DavinciError>>),
+    /// An error, returned to a request of any kind.
+    Error(DavinciError),
+}
+
+impl MockReply {
+    fn kind(&self) -> &'static str {
+        match self {
+            MockReply::Completion(_) => "a completion",
+            MockReply::CompletionStream(_) => "a completion stream",
+            MockReply::Chat(_) => "a chat completion",
+            MockReply::ChatStream(_) => "a chat completion stream",
+            MockReply::Error(_) => "an error",
+        }
+    }
+}
+
+/// A request received by a [`MockBackend`], as it was given.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MockRequest {
+    /// A completion request, with `stream` set to `true` if it was streamed.
+    Completion(CompletionRequest),
+    /// A chat completion request, with `stream` set to `true` if it was streamed.
+    Chat(ChatCompletionRequest),
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    replies: VecDeque<MockReply>,
+    requests: Vec<MockRequest>,
+}
+
+/// A [`CompletionBackend`] that answers with scripted replies, in order,
+/// and records every request it receives. Every clone shares the same script.
+///
+/// A request whose kind does not match the next reply, or that comes when no reply is left,
+/// fails with [`DavinciError::InvalidInput`]; the request is recorded anyway.
+#[derive(Clone, Default)]
+pub struct MockBackend {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl fmt::Debug for MockBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.lock();
+        f.debug_struct("MockBackend")
+            .field("replies", &state.replies.len())
+            .field("requests", &state.requests.len())
+            .finish()
+    }
+}
+
+impl MockBackend {
+    /// Returns a backend with no reply.
+    pub fn new() -> MockBackend {
+        MockBackend::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MockState> {
+        lock(&self.state)
+    }
+
+    /// Adds a reply at the end of the script.
+    pub fn push(&self, reply: MockReply) -> &Self {
+        self.lock().replies.push_back(reply);
+        self
+    }
+
+    /// Adds a completion response with one choice of `text`.
+    pub fn push_completion(&self, text: impl Into<String>) -> &Self {
+        self.push(MockReply::Completion(CompletionResponse {
+            id: "mock".to_string(),
+            object: "text_completion".to_string(),
+            created: 0,
+            model: "mock".to_string(),
+            choices: vec![Choice {
+                text: text.into(),
+                index: 0,
+                logprobs: None,
+                finish_reason: Some(FinishReason::Stop),
+            }],
+            usage: None,
+            system_fingerprint: None,
+        }))
+    }
+
+    /// Adds a completion stream with one chunk for every part of the text.
+    pub fn push_completion_stream<I>(&self, parts: I) -> &Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let chunks = parts
+            .into_iter()
+            .map(|part| {
+                Ok(CompletionChunk {
+                    id: "mock".to_string(),
+                    object: "text_completion".to_string(),
+                    created: 0,
+                    model: "mock".to_string(),
+                    choices: vec![Choice {
+                        text: part.into(),
+                        index: 0,
+                        logprobs: None,
+                        finish_reason: None,
+                    }],
+                    usage: None,
+                })
+            })
+            .collect();
+        self.push(MockReply::CompletionStream(chunks))
+    }
+
+    /// Adds a chat completion response with one assistant message of `text`.
+    pub fn push_chat(&self, text: impl Into<String>) -> &Self {
+        self.push(MockReply::Chat(ChatCompletionResponse {
+            id: "mock".to_string(),
+            object: "chat.completion".to_string(),
+            created: 0,
+            model: "mock".to_string(),
+            choices: vec![ChatChoice {
+                index: 0,
+                message: ChatMessage::new(Role::Assistant, text),
+                finish_reason: Some(FinishReason::Stop),
+            }],
+            usage: None,
+            system_fingerprint: None,
+        }))
+    }
+
+    /// Adds a chat completion stream with one chunk for every part of the text.
+    pub fn push_chat_stream<I>(&self, parts: I) -> &Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let chunks = parts
+            .into_iter()
+            .enumerate()
+            .map(|(index, part)| {
+                Ok(ChatCompletionChunk {
+                    id: "mock".to_string(),
+                    object: "chat.completion.chunk".to_string(),
+                    created: 0,
+                    model: "mock".to_string(),
+                    choices: vec![ChatChunkChoice {
+                        index: 0,
+                        delta: ChatDelta {
+                            role: (index == 0).then_some(Role::Assistant),
+                            content: Some(part.into()),
+                            tool_calls: None,
+                        },
+                        finish_reason: None,
+                    }],
+                    usage: None,
+                })
+            })
+            .collect();
+        self.push(MockReply::ChatStream(chunks))
+    }
+
+    /// Adds an error, returned to the next request whatever its kind.
+    pub fn push_error(&self, error: DavinciError) -> &Self {
+        self.push(MockReply::Error(error))
+    }
+
+    /// Returns the requests received so far, in order.
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.lock().requests.clone()
+    }
+
+    /// Returns how many replies are left in the script.
+    pub fn remaining(&self) -> usize {
+        self.lock().replies.len()
+    }
+
+    /// Records `request` and takes the next reply, which must be of the `expected` kind.
+    fn reply<T>(
+        &self,
+        request: MockRequest,
+        expected: &str,
+        take: impl FnOnce(MockReply) -> Result<T, Box<MockReply>>,
+    ) -> Result<T, DavinciError> {
+        let mut state = self.lock();
+        state.requests.push(request);
+
+        let reply = match state.replies.pop_front() {
+            Some(MockReply::Error(error)) => return Err(error),
+            Some(reply) => reply,
+            None => {
+                return Err(DavinciError::InvalidInput(format!(
+                    "the mock backend has no reply left for {} request",
+                    expected
+                )))
+            }
+        };
+        take(reply).map_err(|reply| {
+            let message = format!(
+                "the next reply of the mock backend is {}, not {}",
+                reply.kind(),
+                expected
+            );
+            state.replies.push_front(*reply);
+            DavinciError::InvalidInput(message)
+        })
+    }
+}
+
+impl CompletionBackend for MockBackend {
+    fn create_completion<'a>(
+        &'a self,
+        request: &'a CompletionRequest,
+    ) -> BoxFuture<'a, Result<CompletionResponse, DavinciError>> {
+        let reply = self.reply(
+            MockRequest::Completion(request.clone()),
+            "a completion",
+            |reply| match reply {
+                MockReply::Completion(response) => Ok(response),
+                reply => Err(Box::new(reply)),
+            },
+        );
+        Box::pin(async move { reply })
+    }
+
+    fn stream_completion<'a>(
+        &'a self,
+        request: &'a CompletionRequest,
+    ) -> BoxFuture<'a, Result<CompletionStream, DavinciError>> {
+        let mut request = request.clone();
+        request.stream = Some(true);
+        StreamOptions::include_usage(&mut request.stream_options);
+        let reply = self.reply(
+            MockRequest::Completion(request),
+            "a completion stream",
+            |reply| match reply {
+                MockReply::CompletionStream(chunks) => {
+                    Ok(CompletionStream::new(stream::iter(chunks)))
+                }
+                reply => Err(Box::new(reply)),
+            },
+        );
+        Box::pin(async move { reply })
+    }
+
+    fn create_chat_completion<'a>(
+        &'a self,
+        request: &'a ChatCompletionRequest,
+    ) -> BoxFuture<'a, Result<ChatCompletionResponse, DavinciError>> {
+        let reply = self.reply(
+            MockRequest::Chat(request.clone()),
+            "a chat completion",
+            |reply| match reply {
+                MockReply::Chat(response) => Ok(response),
+                reply => Err(Box::new(reply)),
+            },
+        );
+        Box::pin(async move { reply })
+    }
+
+    fn stream_chat<'a>(
+        &'a self,
+        request: &'a ChatCompletionRequest,
+    ) -> BoxFuture<'a, Result<ChatCompletionStream, DavinciError>> {
+        let mut request = request.clone();
+        request.stream = Some(true);
+        StreamOptions::include_usage(&mut request.stream_options);
+        let reply = self.reply(
+            MockRequest::Chat(request),
+            "a chat completion stream",
+            |reply| match reply {
+                MockReply::ChatStream(chunks) => {
+                    Ok(ChatCompletionStream::new(stream::iter(chunks)))
+                }
+                reply => Err(Box::new(reply)),
+            },
+        );
+        Box::pin(async move { reply })
+    }
+}
@@ -0,0 +1,277 @@
+//! Blocking version of the API, for callers that do not use async.
+//!
+//! The functions in this module run the async API on a runtime owned by the crate,
+//! which is created the first time it is needed and reused by every later call.
+//!
+//! They must not be called from inside an async runtime, as blocking a runtime thread
+//! would make it panic. Async callers should `.await` the functions at the root of the crate.
+//!
+//! ```no_run
+//! use davinci::blocking::davinci;
+//!
+//! let response = davinci(
+//!     String::from("vj-JZkjskhdksKXOlncknjckukNKKnkJNKJNkNKNk"),
+//!     String::from("The assistant is helpful, creative, clever, and very friendly"),
+//!     String::from("Hello, who are you?"),
+//!     100,
+//! );
+//! ```
+use crate::{
+    CancellationToken, ChatCompletionRequest, ChatCompletionResponse, Choice, CompletionChunk,
+    CompletionRequest, CompletionResponse, Conversation, DavinciClientBuilder, DavinciError,
+};
+use futures::StreamExt;
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::runtime::{Builder, Runtime};
+
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("davinci-blocking")
+            .build()
+            .expect("Error while starting the davinci runtime")
+    })
+}
+
+/// Runs a future of the async API to completion on the crate's runtime.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    runtime().block_on(future)
+}
+
+/// Blocking version of [`crate::davinci`].
+///
+/// # Parameters
+///
+/// * `api_key` - The OpenAI API key.
+/// * `context` - The context for the question.
+/// * `question` - The question or phrase to ask the model.
+/// * `tokens` - The maximum number of tokens to use in the response.
+///
+/// # Returns
+///
+/// Returns the model's response as a Ok(String) or a [`DavinciError`].
+///
+/// # Panics
+///
+/// Panics if it is called from inside an async runtime.
+pub fn davinci(
+    api_key: String,
+    context: String,
+    question: String,
+    tokens: i32,
+) -> Result<String, DavinciError> {
+    block_on(crate::davinci(api_key, context, question, tokens))
+}
+
+/// Blocking version of [`crate::DavinciClient`].
+///
+/// It wraps an async client, so both can be built from the same [`DavinciClientBuilder`]
+/// and share one connection pool.
+#[derive(Debug, Clone)]
+pub struct DavinciClient {
+    inner: crate::DavinciClient,
+}
+
+impl DavinciClient {
+    /// Returns a builder to configure a new client.
+    /// Turn the built async client into a blocking one with `DavinciClient::from`.
+    pub fn builder() -> DavinciClientBuilder {
+        crate::DavinciClient::builder()
+    }
+
+    /// Returns a client with the default configuration and the given api key.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DavinciError::InvalidInput`] if `api_key` is empty.
+    pub fn new(api_key: impl Into<String>) -> Result<DavinciClient, DavinciError> {
+        crate::DavinciClient::new(api_key).map(DavinciClient::from)
+    }
+
+    /// Returns the async client this one wraps.
+    pub fn as_async(&self) -> &crate::DavinciClient {
+        &self.inner
+    }
+
+    /// See [`crate::DavinciClient::with_timeout`].
+    pub fn with_timeout(&self, timeout: Duration) -> DavinciClient {
+        DavinciClient::from(self.inner.with_timeout(timeout))
+    }
+
+    /// See [`crate::DavinciClient::with_connect_timeout`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DavinciError::Transport`] if the connection pool could not be built.
+    pub fn with_connect_timeout(
+        &self,
+        connect_timeout: Duration,
+    ) -> Result<DavinciClient, DavinciError> {
+        self.inner
+            .with_connect_timeout(connect_timeout)
+            .map(DavinciClient::from)
+    }
+
+    /// See [`crate::DavinciClient::with_cancellation`].
+    /// The token can be cancelled from another thread.
+    pub fn with_cancellation(&self, cancellation: CancellationToken) -> DavinciClient {
+        DavinciClient::from(self.inner.with_cancellation(cancellation))
+    }
+
+    /// See [`crate::DavinciClient::without_cache`].
+    pub fn without_cache(&self) -> DavinciClient {
+        DavinciClient::from(self.inner.without_cache())
+    }
+
+    /// Blocking version of [`crate::DavinciClient::ask`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if it is called from inside an async runtime.
+    pub fn ask(&self, context: &str, question: &str, tokens: i32) -> Result<String, DavinciError> {
+        block_on(self.inner.ask(context, question, tokens))
+    }
+
+    /// Same as [`crate::DavinciClient::conversation`].
+    pub fn conversation(&self, context: impl Into<String>) -> Conversation {
+        self.inner.conversation(context)
+    }
+
+    /// Blocking version of [`crate::DavinciClient::ask_conversation`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if it is called from inside an async runtime.
+    pub fn ask_conversation(
+        &self,
+        conversation: &mut Conversation,
+        question: &str,
+        tokens: i32,
+    ) -> Result<String, DavinciError> {
+        block_on(self.inner.ask_conversation(conversation, question, tokens))
+    }
+
+    /// Blocking version of [`crate::DavinciClient::chat_conversation`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if it is called from inside an async runtime.
+    pub fn chat_conversation(
+        &self,
+        conversation: &mut Conversation,
+        question: &str,
+        tokens: i32,
+    ) -> Result<String, DavinciError> {
+        block_on(self.inner.chat_conversation(conversation, question, tokens))
+    }
+
+    /// Blocking version of [`crate::DavinciClient::complete`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if it is called from inside an async runtime.
+    pub fn complete(&self, request: &CompletionRequest) -> Result<String, DavinciError> {
+        block_on(self.inner.complete(request))
+    }
+
+    /// Blocking version of [`crate::DavinciClient::create_completion`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if it is called from inside an async runtime.
+    pub fn create_completion(
+        &self,
+        request: &CompletionRequest,
+    ) -> Result<CompletionResponse, DavinciError> {
+        block_on(self.inner.create_completion(request))
+    }
+
+    /// Blocking version of [`crate::DavinciClient::complete_batch`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if it is called from inside an async runtime.
+    pub fn complete_batch<I>(&self, prompts: I) -> Result<Vec<String>, DavinciError>
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        block_on(self.inner.complete_batch(prompts))
+    }
+
+    /// Blocking version of [`crate::DavinciClient::create_completion_batch`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if it is called from inside an async runtime.
+    pub fn create_completion_batch(
+        &self,
+        request: &CompletionRequest,
+    ) -> Result<Vec<Vec<Choice>>, DavinciError> {
+        block_on(self.inner.create_completion_batch(request))
+    }
+
+    /// Blocking version of [`crate::DavinciClient::stream_completion`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if it, or the returned iterator, is used from inside an async runtime.
+    pub fn stream_completion(
+        &self,
+        request: &CompletionRequest,
+    ) -> Result<CompletionStream, DavinciError> {
+        block_on(self.inner.stream_completion(request)).map(|inner| CompletionStream { inner })
+    }
+
+    /// Blocking version of [`crate::DavinciClient::chat`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if it is called from inside an async runtime.
+    pub fn chat(&self, request: &ChatCompletionRequest) -> Result<String, DavinciError> {
+        block_on(self.inner.chat(request))
+    }
+
+    /// Blocking version of [`crate::DavinciClient::create_chat_completion`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if it is called from inside an async runtime.
+    pub fn create_chat_completion(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse, DavinciError> {
+        block_on(self.inner.create_chat_completion(request))
+    }
+}
+
+impl From<crate::DavinciClient> for DavinciClient {
+    fn from(inner: crate::DavinciClient) -> Self {
+        DavinciClient { inner }
+    }
+}
+
+/// Blocking version of [`crate::CompletionStream`]: an iterator over the chunks of a completion.
+#[derive(Debug)]
+pub struct CompletionStream {
+    inner: crate::CompletionStream,
+}
+
+impl CompletionStream {
+    /// Blocking version of [`crate::CompletionStream::collect_response`].
+    pub fn collect_response(self) -> Result<CompletionResponse, DavinciError> {
+        block_on(self.inner.collect_response())
+    }
+}
+
+impl Iterator for CompletionStream {
+    type Item = Result<CompletionChunk, DavinciError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        block_on(self.inner.next())
+    }
+}
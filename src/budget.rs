@@ -0,0 +1,624 @@
+//! A limit on what a client can spend, in dollars or tokens.
+//!
+//! A [`Budget`] given to [`crate::DavinciClient`] is checked before every request: the client
+//! estimates what the request can cost, as [`crate::DavinciClient::estimate_cost`] does, and
+//! refuses it with [`crate::DavinciError::BudgetExceeded`] if the budget does not have that much
+//! left. Once the response arrives, the estimate is replaced by what its [`crate::Usage`] says
+//! was spent. A stream is charged the usage of its last chunk, if the server sends one,
+//! or else its estimate. Dollars are counted with the [`crate::PricingTable`] of the client.
+//!
+//! The budget covers everything spent, or only what was spent during the last
+//! [`BudgetBuilder::rolling`] window. With [`BudgetBuilder::persist`], what was spent is saved
+//! to a file after every request and loaded again by the next run.
+//!
+//! ```no_run
+//! use davinci::{Budget, DavinciClient};
+//! use std::time::Duration;
+//!
+//! # fn run() -> Result<(), davinci::DavinciError> {
+//! let budget = Budget::builder()
+//!     .dollars(20.0)
+//!     .rolling(Duration::from_secs(24 * 3600))
+//!     .persist("budget.json")
+//!     .build()?;
+//!
+//! let client = DavinciClient::builder()
+//!     .api_key("vj-JZkjskhdksKXOlncknjckukNKKnkJNKJNkNKNk")
+//!     .budget(budget.clone())
+//!     .build()?;
+//!
+//! println!("${:.2} left today", budget.remaining());
+//! # Ok(())
+//! # }
+//! ```
+use crate::persist::{unix_millis, write_atomic};
+use crate::sync::lock;
+use crate::{DavinciError, ModelPrice, Usage};
+use futures::stream::{BoxStream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How much a [`Budget`] allows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BudgetLimit {
+    /// A number of dollars, at the prices of the client's [`crate::PricingTable`].
+    Dollars(f64),
+    /// A number of tokens, of the prompts and of the answers.
+    Tokens(u64),
+}
+
+impl BudgetLimit {
+    /// Returns the limit as a number of dollars or tokens.
+    pub fn amount(&self) -> f64 {
+        match self {
+            BudgetLimit::Dollars(dollars) => *dollars,
+            BudgetLimit::Tokens(tokens) => *tokens as f64,
+        }
+    }
+
+    /// Formats `amount` in the unit of the limit.
+    pub(crate) fn format(&self, amount: f64) -> String {
+        match self {
+            // Rounded to the millionth of a dollar, to hide the errors of the floating point sums.
+            BudgetLimit::Dollars(_) => format!("${}", (amount * 1e6).round() / 1e6),
+            BudgetLimit::Tokens(_) => format!("{} tokens", amount.round()),
+        }
+    }
+}
+
+/// An amount spent at a time, in milliseconds since the Unix epoch.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Spend {
+    at: u64,
+    amount: f64,
+}
+
+/// What a budget has spent, as it is saved to its file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Spending {
+    /// Everything spent.
+    #[serde(default)]
+    total: f64,
+    /// What was spent during the rolling window, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    recent: Vec<Spend>,
+}
+
+#[derive(Debug)]
+struct State {
+    spending: Spending,
+    /// The estimates of the requests in flight.
+    reserved: f64,
+}
+
+/// A limit on what one or more clients can spend, shared by every clone.
+///
+/// Build one with [`Budget::builder`].
+#[derive(Clone)]
+pub struct Budget {
+    limit: BudgetLimit,
+    window: Option<Duration>,
+    state: Arc<Mutex<State>>,
+    file: Option<Arc<BudgetFile>>,
+}
+
+/// The file where a budget saves what was spent.
+struct BudgetFile {
+    path: PathBuf,
+    state: Arc<Mutex<State>>,
+    /// Held while the file is written, so that the saves happen one at a time, in order.
+    saving: Mutex<()>,
+    /// Whether what was spent changed since the last save started.
+    dirty: AtomicBool,
+    /// Whether a save is scheduled and has not started yet, so that it also writes
+    /// the changes made until it starts.
+    scheduled: AtomicBool,
+}
+
+impl BudgetFile {
+    fn save(&self) -> Result<(), DavinciError> {
+        // The snapshot is taken once the previous save is over, so that an older snapshot
+        // is never written over a newer one.
+        let _saving = lock(&self.saving);
+        self.dirty.store(false, Ordering::Release);
+        let json = serde_json::to_vec_pretty(&lock(&self.state).spending)?;
+        write_atomic(&self.path, &json).map_err(|error| DavinciError::io(&self.path, error))
+    }
+}
+
+impl Drop for BudgetFile {
+    /// Writes the changes of a save that never ran, such as when the runtime shut down first.
+    fn drop(&mut self) {
+        if *self.dirty.get_mut() {
+            let _ = self.save();
+        }
+    }
+}
+
+impl fmt::Debug for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Budget")
+            .field("limit", &self.limit)
+            .field("window", &self.window)
+            .field("path", &self.file.as_ref().map(|file| &file.path))
+            .field("spent", &self.spent())
+            .finish()
+    }
+}
+
+impl Budget {
+    /// Returns a builder to configure a new budget.
+    pub fn builder() -> BudgetBuilder {
+        BudgetBuilder::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        lock(&self.state)
+    }
+
+    /// Returns how much the budget allows.
+    pub fn limit(&self) -> BudgetLimit {
+        self.limit
+    }
+
+    /// Returns the rolling window of the budget, or `None` if it covers everything spent.
+    pub fn window(&self) -> Option<Duration> {
+        self.window
+    }
+
+    /// Returns what was spent, during the rolling window if the budget has one,
+    /// in dollars or tokens. The requests in flight are not counted.
+    pub fn spent(&self) -> f64 {
+        let mut state = self.lock();
+        self.spent_locked(&mut state)
+    }
+
+    /// Returns what is left to spend, in dollars or tokens, the requests in flight deducted.
+    pub fn remaining(&self) -> f64 {
+        let mut state = self.lock();
+        let spent = self.spent_locked(&mut state);
+        (self.limit.amount() - spent - state.reserved).max(0.0)
+    }
+
+    /// Forgets everything spent, and saves the empty budget to its file.
+    ///
+    /// # Errors
+    ///
+    /// See [`Budget::save`].
+    pub fn reset(&self) -> Result<(), DavinciError> {
+        self.lock().spending = Spending::default();
+        self.save()
+    }
+
+    /// Saves what was spent to the file of the budget, if it has one.
+    ///
+    /// The client saves the budget after every request on a blocking thread of the runtime,
+    /// so that the requests do not wait for the disk, and ignores the errors, so that
+    /// a request that was paid for is not reported as failed. A save that has not run yet
+    /// when the last clone of the budget is dropped is done then.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DavinciError::Io`] if the file can not be written.
+    pub fn save(&self) -> Result<(), DavinciError> {
+        match &self.file {
+            Some(file) => file.save(),
+            None => Ok(()),
+        }
+    }
+
+    /// Saves the budget without blocking the caller: on a blocking thread of the Tokio runtime
+    /// of the caller, or right away outside of one. The changes made before a scheduled save
+    /// starts are written by it, instead of scheduling one save for each.
+    fn save_later(&self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        file.dirty.store(true, Ordering::Release);
+        if file.scheduled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let file = file.clone();
+        let save = move || {
+            file.scheduled.store(false, Ordering::Release);
+            let _ = file.save();
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn_blocking(save);
+            }
+            Err(_) => save(),
+        }
+    }
+
+    /// Returns what was spent, after forgetting what is older than the window.
+    fn spent_locked(&self, state: &mut State) -> f64 {
+        match self.window {
+            None => state.spending.total,
+            Some(window) => {
+                let start = unix_millis().saturating_sub(window.as_millis() as u64);
+                state.spending.recent.retain(|spend| spend.at > start);
+                state.spending.recent.iter().map(|spend| spend.amount).sum()
+            }
+        }
+    }
+
+    /// Reserves `amount` for a request, or refuses it if the budget does not have that much left.
+    ///
+    /// `price` is the price of the model of the request, for a budget in dollars.
+    pub(crate) fn reserve(
+        &self,
+        amount: f64,
+        price: Option<ModelPrice>,
+    ) -> Result<Reservation, DavinciError> {
+        let mut state = self.lock();
+        let spent = self.spent_locked(&mut state) + state.reserved;
+        if spent + amount > self.limit.amount() {
+            return Err(DavinciError::BudgetExceeded {
+                limit: self.limit,
+                spent,
+                requested: amount,
+            });
+        }
+        state.reserved += amount;
+        Ok(Reservation {
+            budget: self.clone(),
+            amount,
+            price,
+        })
+    }
+}
+
+/// The estimate of a request in flight, given back to the budget if the request fails.
+pub(crate) struct Reservation {
+    budget: Budget,
+    amount: f64,
+    /// The price of the model of the request, for a budget in dollars.
+    price: Option<ModelPrice>,
+}
+
+impl Reservation {
+    /// Records what the request spent, from its usage or from the estimate if it is not known,
+    /// and schedules a save of the budget.
+    pub(crate) fn settle(self, usage: Option<&Usage>) {
+        let spent = usage.map_or(self.amount, |usage| match &self.price {
+            Some(price) => usage.cost(price),
+            None => f64::from(usage.total_tokens),
+        });
+        {
+            let mut state = self.budget.lock();
+            state.spending.total += spent;
+            if self.budget.window.is_some() {
+                state.spending.recent.push(Spend {
+                    at: unix_millis(),
+                    amount: spent,
+                });
+            }
+        }
+        self.budget.save_later();
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut state = self.budget.lock();
+        state.reserved = (state.reserved - self.amount).max(0.0);
+    }
+}
+
+/// The reservation of a stream, charged its estimate if the stream is dropped
+/// before a chunk with the usage arrives.
+struct StreamCharge(Option<Reservation>);
+
+impl Drop for StreamCharge {
+    fn drop(&mut self) {
+        if let Some(reservation) = self.0.take() {
+            reservation.settle(None);
+        }
+    }
+}
+
+/// Charges the reservation of a stream with the usage of the first chunk that has one,
+/// or with its estimate if the stream ends, fails or is dropped without any.
+pub(crate) fn charge_stream<S, T>(
+    stream: S,
+    reservation: Reservation,
+    usage: fn(&T) -> Option<&Usage>,
+) -> BoxStream<'static, Result<T, DavinciError>>
+where
+    S: Stream<Item = Result<T, DavinciError>> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut charge = StreamCharge(Some(reservation));
+    stream
+        .inspect(move |item| {
+            if let Some(usage) = item.as_ref().ok().and_then(usage) {
+                if let Some(reservation) = charge.0.take() {
+                    reservation.settle(Some(usage));
+                }
+            }
+        })
+        .boxed()
+}
+
+/// A builder for [`Budget`].
+#[derive(Debug, Clone, Default)]
+pub struct BudgetBuilder {
+    limit: Option<BudgetLimit>,
+    window: Option<Duration>,
+    path: Option<PathBuf>,
+}
+
+impl BudgetBuilder {
+    /// Sets the limit to a number of dollars.
+    pub fn dollars(mut self, dollars: f64) -> Self {
+        self.limit = Some(BudgetLimit::Dollars(dollars));
+        self
+    }
+
+    /// Sets the limit to a number of tokens.
+    pub fn tokens(mut self, tokens: u64) -> Self {
+        self.limit = Some(BudgetLimit::Tokens(tokens));
+        self
+    }
+
+    /// Sets the budget to only count what was spent during the last `window`,
+    /// such as a day. By default it counts everything spent.
+    pub fn rolling(mut self, window: Duration) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    /// Sets the file where what was spent is saved, and loaded from when the budget is built.
+    pub fn persist(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Builds the budget, with what was spent loaded from its file if it exists.
+    ///
+    /// # Errors
+    ///
+    /// * [`DavinciError::InvalidInput`] if the limit is not set or not positive,
+    ///   or the window is zero.
+    /// * [`DavinciError::Io`] if the file can not be read.
+    /// * [`DavinciError::Deserialize`] if the file is not a saved budget.
+    pub fn build(self) -> Result<Budget, DavinciError> {
+        let limit = match self.limit {
+            Some(limit) if limit.amount() > 0.0 && limit.amount().is_finite() => limit,
+            Some(_) => {
+                return Err(DavinciError::InvalidInput(String::from(
+                    "the budget must be positive",
+                )))
+            }
+            None => {
+                return Err(DavinciError::InvalidInput(String::from(
+                    "the budget needs a limit in dollars or tokens",
+                )))
+            }
+        };
+        if self.window == Some(Duration::ZERO) {
+            return Err(DavinciError::InvalidInput(String::from(
+                "the rolling window of the budget must be positive",
+            )));
+        }
+
+        let spending = match &self.path {
+            Some(path) if path.exists() => load(path)?,
+            _ => Spending::default(),
+        };
+
+        let state = Arc::new(Mutex::new(State {
+            spending,
+            reserved: 0.0,
+        }));
+        Ok(Budget {
+            limit,
+            window: self.window,
+            file: self.path.map(|path| {
+                Arc::new(BudgetFile {
+                    path,
+                    state: state.clone(),
+                    saving: Mutex::new(()),
+                    dirty: AtomicBool::new(false),
+                    scheduled: AtomicBool::new(false),
+                })
+            }),
+            state,
+        })
+    }
+}
+
+fn load(path: &Path) -> Result<Spending, DavinciError> {
+    let json = fs::read(path).map_err(|error| DavinciError::io(path, error))?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+
+    fn usage(prompt_tokens: u32, completion_tokens: u32) -> Usage {
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            ..Usage::default()
+        }
+    }
+
+    fn temporary_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "davinci-budget-{}-{}.json",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn reserve_refuses_more_than_is_left_and_drop_gives_it_back() {
+        let budget = Budget::builder().tokens(100).build().unwrap();
+
+        let reservation = budget.reserve(60.0, None).unwrap();
+        assert_eq!(budget.remaining(), 40.0);
+        assert!(matches!(
+            budget.reserve(50.0, None),
+            Err(DavinciError::BudgetExceeded { spent, requested, .. })
+                if spent == 60.0 && requested == 50.0
+        ));
+
+        drop(reservation);
+        assert_eq!(budget.remaining(), 100.0);
+        assert_eq!(budget.spent(), 0.0);
+    }
+
+    #[test]
+    fn settle_charges_the_usage_or_else_the_estimate() {
+        let budget = Budget::builder().tokens(1_000).build().unwrap();
+
+        budget
+            .reserve(500.0, None)
+            .unwrap()
+            .settle(Some(&usage(20, 5)));
+        assert_eq!(budget.spent(), 25.0);
+
+        budget.reserve(100.0, None).unwrap().settle(None);
+        assert_eq!(budget.spent(), 125.0);
+        assert_eq!(budget.remaining(), 875.0);
+    }
+
+    #[test]
+    fn settle_charges_dollars_at_the_price_of_the_model() {
+        let budget = Budget::builder().dollars(1.0).build().unwrap();
+        let price = ModelPrice::new(1.0, 2.0);
+
+        budget
+            .reserve(0.5, Some(price))
+            .unwrap()
+            .settle(Some(&usage(1_000, 500)));
+        assert!((budget.spent() - 0.002).abs() < 1e-12);
+    }
+
+    #[test]
+    fn rolling_window_forgets_what_is_older() {
+        let budget = Budget::builder()
+            .tokens(100)
+            .rolling(Duration::from_secs(60))
+            .build()
+            .unwrap();
+        budget.lock().spending.recent.push(Spend {
+            at: unix_millis() - 61_000,
+            amount: 80.0,
+        });
+        budget.reserve(30.0, None).unwrap().settle(None);
+
+        assert_eq!(budget.spent(), 30.0);
+        assert_eq!(budget.lock().spending.recent.len(), 1);
+        assert!(budget.reserve(70.0, None).is_ok());
+    }
+
+    #[test]
+    fn persisted_budget_is_loaded_again() {
+        let path = temporary_path("round-trip");
+        let budget = Budget::builder()
+            .tokens(1_000)
+            .rolling(Duration::from_secs(3600))
+            .persist(&path)
+            .build()
+            .unwrap();
+        budget
+            .reserve(50.0, None)
+            .unwrap()
+            .settle(Some(&usage(30, 12)));
+        // Outside of a runtime, the save happens right away.
+        let reloaded = Budget::builder()
+            .tokens(1_000)
+            .rolling(Duration::from_secs(3600))
+            .persist(&path)
+            .build()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(reloaded.spent(), 42.0);
+        assert_eq!(reloaded.lock().spending.total, 42.0);
+    }
+
+    #[tokio::test]
+    async fn settle_saves_on_a_blocking_thread() {
+        let path = temporary_path("background");
+        let budget = Budget::builder()
+            .tokens(1_000)
+            .persist(&path)
+            .build()
+            .unwrap();
+        budget.reserve(10.0, None).unwrap().settle(None);
+
+        let mut saved = None;
+        for _ in 0..100 {
+            if let Some(spending) = fs::read(&path)
+                .ok()
+                .and_then(|json| serde_json::from_slice::<Spending>(&json).ok())
+            {
+                saved = Some(spending.total);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(saved, Some(10.0));
+    }
+
+    #[test]
+    fn unsaved_changes_are_written_when_the_budget_is_dropped() {
+        let path = temporary_path("drop");
+        let budget = Budget::builder()
+            .tokens(1_000)
+            .persist(&path)
+            .build()
+            .unwrap();
+        // As if the save scheduled by a request never ran.
+        budget.lock().spending.total = 10.0;
+        let file = budget.file.as_ref().unwrap();
+        file.dirty.store(true, Ordering::Release);
+        file.scheduled.store(true, Ordering::Release);
+        assert!(!path.exists());
+
+        drop(budget);
+        let saved: Spending = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(saved.total, 10.0);
+    }
+
+    #[tokio::test]
+    async fn charge_stream_settles_with_the_usage_of_a_chunk() {
+        let budget = Budget::builder().tokens(1_000).build().unwrap();
+        let reservation = budget.reserve(500.0, None).unwrap();
+        let chunks = vec![Ok(None), Ok(Some(usage(7, 3))), Ok(None)];
+        let stream = charge_stream(stream::iter(chunks), reservation, Option::as_ref);
+
+        assert_eq!(budget.remaining(), 500.0);
+        assert_eq!(stream.count().await, 3);
+        assert_eq!(budget.spent(), 10.0);
+        assert_eq!(budget.remaining(), 990.0);
+    }
+
+    #[tokio::test]
+    async fn charge_stream_charges_the_estimate_without_usage() {
+        let budget = Budget::builder().tokens(1_000).build().unwrap();
+        let reservation = budget.reserve(500.0, None).unwrap();
+        let chunks: Vec<Result<Option<Usage>, DavinciError>> =
+            vec![Ok(None), Err(DavinciError::EmptyChoices)];
+        let stream = charge_stream(stream::iter(chunks), reservation, Option::as_ref);
+
+        drop(stream);
+        assert_eq!(budget.spent(), 500.0);
+    }
+}
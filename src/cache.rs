@@ -0,0 +1,486 @@
+//! A cache of the responses, so that the same request is not paid for twice.
+//!
+//! A [`ResponseCache`] given to [`crate::DavinciClient`] stores the responses of the completion
+//! and chat completion requests, keyed by a stable hash of the endpoint and of the request
+//! as it is sent, defaults and model included. By default only the deterministic requests,
+//! the ones with a temperature of `0`, are cached, as the others are expected to give
+//! a different answer every time. Streamed requests are never cached.
+//!
+//! The responses are kept in memory with [`ResponseCache::memory`], or in a directory
+//! with [`ResponseCache::directory`], so they survive between runs.
+//!
+//! ```no_run
+//! use davinci::{CompletionRequest, DavinciClient, ResponseCache};
+//! use std::time::Duration;
+//!
+//! # async fn run() -> Result<(), davinci::DavinciError> {
+//! let client = DavinciClient::builder()
+//!     .api_key("vj-JZkjskhdksKXOlncknjckukNKKnkJNKJNkNKNk")
+//!     .cache(ResponseCache::directory("target/davinci-cache").ttl(Duration::from_secs(7 * 24 * 3600)))
+//!     .build()?;
+//!
+//! let request = CompletionRequest::builder().prompt("Say hello").temperature(0.0).build()?;
+//! let first = client.complete(&request).await?;
+//! // Served from the cache, without calling the API.
+//! let second = client.complete(&request).await?;
+//! // Sent to the API, and not stored.
+//! let third = client.without_cache().complete(&request).await?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::persist::{unix_millis, write_atomic};
+use crate::sync::lock;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A stored response, with the request it answers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    /// The URL the request was sent to.
+    pub endpoint: String,
+    /// The body of the request, as JSON.
+    pub request: String,
+    /// The body of the response, as JSON.
+    pub response: String,
+    /// The Unix timestamp, in milliseconds, of when the response was stored.
+    pub created: u64,
+}
+
+/// Where a [`ResponseCache`] keeps its entries.
+///
+/// The cache is best effort: a backend that fails to read or write an entry
+/// treats it as missing, and the request is sent to the API.
+pub trait CacheBackend: Send + Sync {
+    /// Returns the entry stored under `key`, if any.
+    fn get(&self, key: &str) -> Option<CacheEntry>;
+
+    /// Stores `entry` under `key`, replacing the previous one.
+    fn put(&self, key: &str, entry: CacheEntry);
+
+    /// Removes the entry stored under `key`, if any.
+    fn remove(&self, key: &str);
+
+    /// Removes every entry.
+    fn clear(&self);
+}
+
+/// A cache of responses, shared by every clone.
+///
+/// Build one with [`ResponseCache::memory`], [`ResponseCache::directory`]
+/// or [`ResponseCache::new`] for a custom backend.
+#[derive(Clone)]
+pub struct ResponseCache {
+    backend: Arc<dyn CacheBackend>,
+    ttl: Option<Duration>,
+    deterministic_only: bool,
+}
+
+impl fmt::Debug for ResponseCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseCache")
+            .field("ttl", &self.ttl)
+            .field("deterministic_only", &self.deterministic_only)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ResponseCache {
+    /// Returns a cache that stores its entries in `backend`.
+    pub fn new(backend: impl CacheBackend + 'static) -> ResponseCache {
+        ResponseCache {
+            backend: Arc::new(backend),
+            ttl: None,
+            deterministic_only: true,
+        }
+    }
+
+    /// Returns a cache that keeps at most `capacity` responses in memory,
+    /// and forgets the least recently used one when it is full.
+    pub fn memory(capacity: usize) -> ResponseCache {
+        ResponseCache::new(MemoryCache::new(capacity))
+    }
+
+    /// Returns a cache that stores every response in a JSON file of `directory`,
+    /// which is created when needed.
+    pub fn directory(directory: impl Into<PathBuf>) -> ResponseCache {
+        ResponseCache::new(DirectoryCache::new(directory))
+    }
+
+    /// Sets how long a response is served from the cache. Default: forever.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Sets whether only the requests with a temperature of `0` are cached. Default: `true`.
+    pub fn deterministic_only(mut self, deterministic_only: bool) -> Self {
+        self.deterministic_only = deterministic_only;
+        self
+    }
+
+    /// Returns `true` if the responses of a request that is deterministic or not are cached.
+    pub(crate) fn accepts(&self, deterministic: bool) -> bool {
+        deterministic || !self.deterministic_only
+    }
+
+    /// Removes every response.
+    pub fn clear(&self) {
+        self.backend.clear();
+    }
+
+    /// Returns the response stored for `request` sent to `endpoint`, if it is still fresh.
+    pub(crate) fn get<B, R>(&self, endpoint: &str, request: &B) -> Option<R>
+    where
+        B: Serialize + ?Sized,
+        R: DeserializeOwned,
+    {
+        let request = serde_json::to_string(request).ok()?;
+        let key = cache_key(endpoint, &request);
+        let entry = self.backend.get(&key)?;
+
+        // The whole request is compared, so that two requests with the same hash
+        // never get each other's response.
+        if entry.endpoint != endpoint || entry.request != request {
+            return None;
+        }
+        if let Some(ttl) = self.ttl {
+            if unix_millis().saturating_sub(entry.created) >= ttl.as_millis() as u64 {
+                self.backend.remove(&key);
+                return None;
+            }
+        }
+        serde_json::from_str(&entry.response).ok()
+    }
+
+    /// Stores the response of `request` sent to `endpoint`.
+    pub(crate) fn put<B, R>(&self, endpoint: &str, request: &B, response: &R)
+    where
+        B: Serialize + ?Sized,
+        R: Serialize,
+    {
+        if let (Ok(request), Ok(response)) = (
+            serde_json::to_string(request),
+            serde_json::to_string(response),
+        ) {
+            let key = cache_key(endpoint, &request);
+            self.backend.put(
+                &key,
+                CacheEntry {
+                    endpoint: endpoint.to_string(),
+                    request,
+                    response,
+                    created: unix_millis(),
+                },
+            );
+        }
+    }
+}
+
+/// Returns the key of a request: the 128-bit FNV-1a hash of its endpoint and body, in hex.
+///
+/// It only depends on the bytes of the request, so it is the same across runs and platforms.
+pub fn cache_key(endpoint: &str, request: &str) -> String {
+    const OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const PRIME: u128 = 0x0000000001000000000000000000013b;
+
+    let mut hash = OFFSET;
+    for byte in endpoint.bytes().chain([b'\n']).chain(request.bytes()) {
+        hash ^= u128::from(byte);
+        hash = hash.wrapping_mul(PRIME);
+    }
+    format!("{:032x}", hash)
+}
+
+/// A backend that keeps the most recently used responses in memory.
+#[derive(Debug)]
+pub struct MemoryCache {
+    capacity: usize,
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    /// The entries and the tick of their last use.
+    entries: HashMap<String, (CacheEntry, u64)>,
+    /// The keys by the tick of their last use, the least recently used first.
+    uses: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl MemoryState {
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        if let Some((_, used)) = self.entries.get_mut(key) {
+            self.uses.remove(used);
+            *used = self.tick;
+            self.uses.insert(self.tick, key.to_string());
+        }
+    }
+}
+
+impl MemoryCache {
+    /// Returns an empty cache that holds at most `capacity` responses.
+    pub fn new(capacity: usize) -> MemoryCache {
+        MemoryCache {
+            capacity,
+            state: Mutex::new(MemoryState::default()),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MemoryState> {
+        lock(&self.state)
+    }
+}
+
+impl CacheBackend for MemoryCache {
+    fn get(&self, key: &str) -> Option<CacheEntry> {
+        let mut state = self.lock();
+        state.touch(key);
+        state.entries.get(key).map(|(entry, _)| entry.clone())
+    }
+
+    fn put(&self, key: &str, entry: CacheEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut state = self.lock();
+        if let Some((_, used)) = state.entries.remove(key) {
+            state.uses.remove(&used);
+        }
+        while state.entries.len() >= self.capacity {
+            match state.uses.pop_first() {
+                Some((_, oldest)) => state.entries.remove(&oldest),
+                None => break,
+            };
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        state.entries.insert(key.to_string(), (entry, tick));
+        state.uses.insert(tick, key.to_string());
+    }
+
+    fn remove(&self, key: &str) {
+        let mut state = self.lock();
+        if let Some((_, used)) = state.entries.remove(key) {
+            state.uses.remove(&used);
+        }
+    }
+
+    fn clear(&self) {
+        *self.lock() = MemoryState::default();
+    }
+}
+
+/// A backend that stores every response in a JSON file named after its key.
+#[derive(Debug, Clone)]
+pub struct DirectoryCache {
+    directory: PathBuf,
+}
+
+impl DirectoryCache {
+    /// Returns a cache in `directory`, which is created when the first response is stored.
+    pub fn new(directory: impl Into<PathBuf>) -> DirectoryCache {
+        DirectoryCache {
+            directory: directory.into(),
+        }
+    }
+
+    /// Returns the directory of the cache.
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.directory.join(format!("{}.json", key))
+    }
+}
+
+/// Returns `true` if `path` is the file of an entry: a key made by [`cache_key`], plus `.json`.
+fn is_entry(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "json")
+        && path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .is_some_and(|stem| {
+                stem.len() == 32 && stem.bytes().all(|byte| byte.is_ascii_hexdigit())
+            })
+}
+
+impl CacheBackend for DirectoryCache {
+    fn get(&self, key: &str) -> Option<CacheEntry> {
+        let file = fs::read(self.path(key)).ok()?;
+        serde_json::from_slice(&file).ok()
+    }
+
+    fn put(&self, key: &str, entry: CacheEntry) {
+        if let Ok(json) = serde_json::to_vec_pretty(&entry) {
+            let _ = write_atomic(&self.path(key), &json);
+        }
+    }
+
+    fn remove(&self, key: &str) {
+        let _ = fs::remove_file(self.path(key));
+    }
+
+    /// Removes the files of the entries, and leaves the other files of the directory alone.
+    fn clear(&self) {
+        if let Ok(files) = fs::read_dir(&self.directory) {
+            for file in files.flatten() {
+                let path = file.path();
+                if is_entry(&path) {
+                    let _ = fs::remove_file(path);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn entry(response: &str) -> CacheEntry {
+        CacheEntry {
+            endpoint: "https://api.openai.com/v1/completions".to_string(),
+            request: "{}".to_string(),
+            response: response.to_string(),
+            created: unix_millis(),
+        }
+    }
+
+    #[test]
+    fn memory_cache_forgets_the_least_recently_used_entry() {
+        let cache = MemoryCache::new(2);
+        cache.put("a", entry("1"));
+        cache.put("b", entry("2"));
+        assert!(cache.get("a").is_some());
+
+        cache.put("c", entry("3"));
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+
+        cache.put("a", entry("4"));
+        assert_eq!(cache.get("a").unwrap().response, "4");
+        assert_eq!(cache.lock().entries.len(), 2);
+    }
+
+    #[test]
+    fn memory_cache_of_no_capacity_stores_nothing() {
+        let cache = MemoryCache::new(0);
+        cache.put("a", entry("1"));
+        assert!(cache.get("a").is_none());
+    }
+
+    #[test]
+    fn responses_older_than_the_ttl_are_removed() {
+        let endpoint = "https://api.openai.com/v1/completions";
+        let request = json!({"prompt": "Say hello", "temperature": 0.0});
+        let cache = ResponseCache::memory(10).ttl(Duration::from_secs(60));
+
+        cache.put(endpoint, &request, &json!({"text": "Hello"}));
+        assert_eq!(
+            cache.get::<_, Value>(endpoint, &request),
+            Some(json!({"text": "Hello"}))
+        );
+
+        let key = cache_key(endpoint, &request.to_string());
+        let mut old = cache.backend.get(&key).unwrap();
+        old.created -= 60_000;
+        cache.backend.put(&key, old);
+        assert_eq!(cache.get::<_, Value>(endpoint, &request), None);
+        assert!(cache.backend.get(&key).is_none());
+    }
+
+    #[test]
+    fn responses_of_another_request_are_not_returned() {
+        let endpoint = "https://api.openai.com/v1/completions";
+        let cache = ResponseCache::memory(10);
+        cache.put(endpoint, &json!({"prompt": "a"}), &json!("A"));
+
+        assert_eq!(
+            cache.get::<_, Value>(endpoint, &json!({"prompt": "b"})),
+            None
+        );
+        assert_eq!(
+            cache.get::<_, Value>(
+                "https://example.com/v1/completions",
+                &json!({"prompt": "a"})
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn only_deterministic_requests_are_accepted_by_default() {
+        let cache = ResponseCache::memory(10);
+        assert!(cache.accepts(true));
+        assert!(!cache.accepts(false));
+        assert!(cache.deterministic_only(false).accepts(false));
+
+        let mut request = crate::CompletionRequest::new("Say hello");
+        assert!(!request.is_deterministic());
+        request.temperature = Some(0.0);
+        assert!(request.is_deterministic());
+        request.stream = Some(true);
+        assert!(!request.is_deterministic());
+    }
+
+    #[tokio::test]
+    async fn client_sends_non_deterministic_requests_again() {
+        let client = crate::DavinciClient::builder()
+            .api_key("sk-test")
+            .cache(ResponseCache::memory(10))
+            .cassette(
+                crate::Cassette::replay(concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/tests/cassettes/chat.json"
+                ))
+                .unwrap(),
+            )
+            .build()
+            .unwrap();
+        let request = crate::ChatCompletionRequest::builder()
+            .message(crate::ChatMessage::user("Say hello"))
+            .max_tokens(5)
+            .build()
+            .unwrap();
+
+        assert_eq!(client.chat(&request).await.unwrap(), "Hello!");
+        // The cassette has no second answer, so the request was not served from the cache.
+        assert!(matches!(
+            client.chat(&request).await,
+            Err(crate::DavinciError::Cassette(_))
+        ));
+    }
+
+    #[test]
+    fn directory_cache_clear_leaves_other_files_alone() {
+        let directory =
+            std::env::temp_dir().join(format!("davinci-cache-clear-{}", std::process::id()));
+        let cache = DirectoryCache::new(&directory);
+        let key = cache_key("endpoint", "request");
+        cache.put(&key, entry("1"));
+        assert_eq!(cache.get(&key).unwrap().response, "1");
+        fs::write(directory.join("notes.json"), "{}").unwrap();
+        fs::write(directory.join("README"), "").unwrap();
+
+        cache.clear();
+        let mut left: Vec<String> = fs::read_dir(&directory)
+            .unwrap()
+            .map(|file| file.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(left, vec!["README", "notes.json"]);
+    }
+}
@@ -0,0 +1,436 @@
+//! Recorded requests and responses, to test code that calls the API without a network.
+//!
+//! A [`Cassette`] given to [`crate::DavinciClient`] in record mode sends the requests as usual
+//! and writes every request and its response to a JSON file, the `Authorization` header
+//! redacted. Streamed responses are stored as the list of their server-sent events.
+//! In replay mode the client never opens a connection: every request is answered with the
+//! first unused recorded response whose method, URL and body match, and a request that matches
+//! none fails with [`crate::DavinciError::Cassette`].
+//!
+//! ```no_run
+//! use davinci::{Cassette, DavinciClient};
+//!
+//! # async fn run() -> Result<(), davinci::DavinciError> {
+//! // Run once with a real key to record the cassette...
+//! let client = DavinciClient::builder()
+//!     .api_key(std::env::var("OPENAI_API_KEY").unwrap_or_default())
+//!     .cassette(Cassette::record("tests/cassettes/hello.json"))
+//!     .build()?;
+//! client.ask("You are a polite assistant", "Say hello", 10).await?;
+//!
+//! // ...then replay it in CI, where any key works.
+//! let client = DavinciClient::builder()
+//!     .api_key("sk-test")
+//!     .cassette(Cassette::replay("tests/cassettes/hello.json")?)
+//!     .build()?;
+//! let answer = client.ask("You are a polite assistant", "Say hello", 10).await?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::sync::lock;
+use crate::DavinciError;
+use futures::stream;
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+use reqwest::{Client, Request, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The value that replaces the secret headers in a cassette.
+pub const REDACTED: &str = "<redacted>";
+
+/// The headers whose value is never written to a cassette.
+const SECRET_HEADERS: &[&str] = &["authorization", "api-key", "openai-organization"];
+
+/// Whether a [`Cassette`] records the requests or replays them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMode {
+    /// Sends the requests and stores them with their responses.
+    Record,
+    /// Answers the requests with the stored responses, without any network access.
+    Replay,
+}
+
+/// A request and the response the server gave to it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Interaction {
+    /// The request, as it was sent.
+    pub request: RecordedRequest,
+    /// The response of the server.
+    pub response: RecordedResponse,
+}
+
+/// A request stored in a cassette.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    /// The HTTP method, such as `POST`.
+    pub method: String,
+    /// The full URL the request was sent to.
+    pub url: String,
+    /// The headers of the request, the secret ones redacted.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// The JSON body of the request, or `null` if it has none.
+    #[serde(default)]
+    pub body: serde_json::Value,
+}
+
+/// A response stored in a cassette.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    /// The HTTP status code.
+    pub status: u16,
+    /// The headers of the response.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// The body of the response, if it was not streamed.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub body: String,
+    /// The server-sent events of a streamed response, in order, each with its trailing blank line.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CassetteFile {
+    interactions: Vec<Interaction>,
+}
+
+#[derive(Debug)]
+struct State {
+    interactions: Vec<Interaction>,
+    /// Whether every interaction has already answered a request, in replay mode.
+    used: Vec<bool>,
+}
+
+/// A file of recorded interactions, shared by every clone.
+///
+/// Build one with [`Cassette::record`] or [`Cassette::replay`].
+#[derive(Clone)]
+pub struct Cassette {
+    path: PathBuf,
+    mode: CassetteMode,
+    state: Arc<Mutex<State>>,
+}
+
+impl fmt::Debug for Cassette {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cassette")
+            .field("path", &self.path)
+            .field("mode", &self.mode)
+            .field("interactions", &self.lock().interactions.len())
+            .finish()
+    }
+}
+
+impl Cassette {
+    /// Returns a cassette that records every request sent by the client to the file at `path`.
+    ///
+    /// The file is replaced, and written again after every request.
+    pub fn record(path: impl Into<PathBuf>) -> Cassette {
+        Cassette {
+            path: path.into(),
+            mode: CassetteMode::Record,
+            state: Arc::new(Mutex::new(State {
+                interactions: Vec::new(),
+                used: Vec::new(),
+            })),
+        }
+    }
+
+    /// Returns a cassette that answers the requests with the interactions of the file at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DavinciError::Cassette`] if the file can not be read or is not a cassette.
+    pub fn replay(path: impl Into<PathBuf>) -> Result<Cassette, DavinciError> {
+        let path = path.into();
+        let file = fs::read(&path).map_err(|error| {
+            DavinciError::Cassette(format!("could not read {}: {}", path.display(), error))
+        })?;
+        let file: CassetteFile = serde_json::from_slice(&file).map_err(|error| {
+            DavinciError::Cassette(format!("could not parse {}: {}", path.display(), error))
+        })?;
+
+        Ok(Cassette {
+            mode: CassetteMode::Replay,
+            state: Arc::new(Mutex::new(State {
+                used: vec![false; file.interactions.len()],
+                interactions: file.interactions,
+            })),
+            path,
+        })
+    }
+
+    /// Returns the path of the file of the cassette.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns whether the cassette records or replays the requests.
+    pub fn mode(&self) -> CassetteMode {
+        self.mode
+    }
+
+    /// Returns the recorded interactions, in the order they happened.
+    pub fn interactions(&self) -> Vec<Interaction> {
+        self.lock().interactions.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        lock(&self.state)
+    }
+
+    /// Sends `request` with `http` and records it, or answers it from the cassette.
+    pub(crate) async fn send(
+        &self,
+        http: &Client,
+        request: Request,
+    ) -> Result<Response, DavinciError> {
+        let recorded = record_request(&request);
+        match self.mode {
+            CassetteMode::Record => {
+                let response = http.execute(request).await?;
+                let response = record_response(response).await?;
+                self.save(Interaction {
+                    request: recorded,
+                    response: response.clone(),
+                })?;
+                into_response(response)
+            }
+            CassetteMode::Replay => into_response(self.find(&recorded)?),
+        }
+    }
+
+    /// Adds an interaction and writes the whole cassette to its file.
+    fn save(&self, interaction: Interaction) -> Result<(), DavinciError> {
+        let mut state = self.lock();
+        state.interactions.push(interaction);
+
+        let file = CassetteFile {
+            interactions: state.interactions.clone(),
+        };
+        let write = || -> std::io::Result<()> {
+            if let Some(directory) = self.path.parent() {
+                fs::create_dir_all(directory)?;
+            }
+            fs::write(&self.path, serde_json::to_vec_pretty(&file)?)
+        };
+        write().map_err(|error| {
+            DavinciError::Cassette(format!(
+                "could not write {}: {}",
+                self.path.display(),
+                error
+            ))
+        })
+    }
+
+    /// Returns the response of the first unused interaction that matches `request`.
+    fn find(&self, request: &RecordedRequest) -> Result<RecordedResponse, DavinciError> {
+        let mut state = self.lock();
+        let State { interactions, used } = &mut *state;
+        let index = interactions
+            .iter()
+            .zip(used.iter())
+            .position(|(interaction, used)| {
+                !used
+                    && interaction.request.method == request.method
+                    && interaction.request.url == request.url
+                    && interaction.request.body == request.body
+            })
+            .ok_or_else(|| {
+                DavinciError::Cassette(format!(
+                    "no unused interaction of {} matches the request {} {} {}",
+                    self.path.display(),
+                    request.method,
+                    request.url,
+                    request.body
+                ))
+            })?;
+        used[index] = true;
+        Ok(interactions[index].response.clone())
+    }
+}
+
+fn record_headers(headers: &HeaderMap) -> BTreeMap<String, String> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if SECRET_HEADERS.contains(&name.as_str()) {
+                REDACTED.to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+fn record_request(request: &Request) -> RecordedRequest {
+    let body = request
+        .body()
+        .and_then(|body| body.as_bytes())
+        .and_then(|body| serde_json::from_slice(body).ok())
+        .unwrap_or_default();
+
+    RecordedRequest {
+        method: request.method().to_string(),
+        url: request.url().to_string(),
+        headers: record_headers(request.headers()),
+        body,
+    }
+}
+
+async fn record_response(response: Response) -> Result<RecordedResponse, DavinciError> {
+    let status = response.status().as_u16();
+    let headers = record_headers(response.headers());
+    let streamed = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/event-stream"));
+    let body = response.text().await?;
+
+    Ok(if streamed {
+        RecordedResponse {
+            status,
+            headers,
+            body: String::new(),
+            chunks: body.split_inclusive("\n\n").map(str::to_string).collect(),
+        }
+    } else {
+        RecordedResponse {
+            status,
+            headers,
+            body,
+            chunks: Vec::new(),
+        }
+    })
+}
+
+fn into_response(recorded: RecordedResponse) -> Result<Response, DavinciError> {
+    let mut builder = http::Response::builder().status(recorded.status);
+    for (name, value) in &recorded.headers {
+        builder = builder.header(name, value);
+    }
+
+    let body = if recorded.chunks.is_empty() {
+        reqwest::Body::from(recorded.body)
+    } else {
+        let chunks = recorded.chunks.into_iter().map(Ok::<_, std::io::Error>);
+        reqwest::Body::wrap_stream(stream::iter(chunks))
+    };
+    let response = builder
+        .body(body)
+        .map_err(|error| DavinciError::Cassette(format!("invalid recorded response: {}", error)))?;
+    Ok(Response::from(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChatCompletionRequest, ChatMessage, DavinciClient};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn request() -> ChatCompletionRequest {
+        ChatCompletionRequest::builder()
+            .message(ChatMessage::user("Say hello"))
+            .max_tokens(5)
+            .build()
+            .unwrap()
+    }
+
+    /// Answers one request on a local port with `body`, and returns the base URL.
+    async fn serve_once(body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            loop {
+                let read = socket.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+                let text = String::from_utf8_lossy(&request).to_lowercase();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length: usize = text
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length:"))
+                        .map_or(0, |length| length.trim().parse().unwrap());
+                    if request.len() >= end + 4 + length {
+                        break;
+                    }
+                }
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn replay_answers_plain_and_streamed_requests_once() {
+        let cassette = Cassette::replay(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/cassettes/chat.json"
+        ))
+        .unwrap();
+        let client = DavinciClient::builder()
+            .api_key("sk-test")
+            .cassette(cassette)
+            .build()
+            .unwrap();
+
+        assert_eq!(client.chat(&request()).await.unwrap(), "Hello!");
+        let stream = client.stream_chat(&request()).await.unwrap();
+        let response = stream.collect_response().await.unwrap();
+        assert_eq!(response.text(), Some("Hello!"));
+        assert_eq!(response.usage.map(|usage| usage.total_tokens), Some(11));
+
+        let error = client.chat(&request()).await.unwrap_err();
+        assert!(
+            matches!(&error, DavinciError::Cassette(message)
+                if message.starts_with("no unused interaction")),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn record_redacts_the_authorization_header() {
+        let body = r#"{"id":"chatcmpl-1","object":"chat.completion","created":1,"model":"gpt-4o-mini","choices":[{"index":0,"message":{"role":"assistant","content":"Hello!"},"finish_reason":"stop"}]}"#;
+        let url = serve_once(body).await;
+        let path = std::env::temp_dir().join(format!(
+            "davinci-cassette-record-{}.json",
+            std::process::id()
+        ));
+        let client = DavinciClient::builder()
+            .api_key("sk-secret")
+            .base_url(&url)
+            .cassette(Cassette::record(&path))
+            .build()
+            .unwrap();
+
+        assert_eq!(client.chat(&request()).await.unwrap(), "Hello!");
+
+        let file = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(!file.contains("sk-secret"));
+        let file: CassetteFile = serde_json::from_str(&file).unwrap();
+        let interaction = &file.interactions[0];
+        assert_eq!(interaction.request.headers["authorization"], REDACTED);
+        assert_eq!(
+            interaction.request.url,
+            format!("{}/v1/chat/completions", url)
+        );
+        assert_eq!(interaction.response.body, body);
+    }
+}
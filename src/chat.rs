@@ -0,0 +1,909 @@
+//! Types of the `/v1/chat/completions` endpoint.
+//!
+//! The legacy `/v1/completions` endpoint and `text-davinci-003` are retired,
+//! so current models are used through chat completions: instead of one prompt,
+//! the request holds a list of [`ChatMessage`]s, each one with a [`Role`].
+//!
+//! ```no_run
+//! use davinci::{ChatCompletionRequest, ChatMessage, DavinciClient};
+//!
+//! # async fn run(client: DavinciClient) -> Result<(), davinci::DavinciError> {
+//! let request = ChatCompletionRequest::builder()
+//!     .message(ChatMessage::system("The assistant is helpful, creative, clever, and very friendly."))
+//!     .message(ChatMessage::user("Hello, who are you?"))
+//!     .max_tokens(100)
+//!     .build()?;
+//!
+//! let answer = client.chat(&request).await?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::completion::{check_range, fill};
+use crate::stream::{json_events, until_cancelled};
+use crate::{
+    CancellationToken, CompletionRequest, DavinciError, FinishReason, PricingTable, Stop,
+    StreamOptions, Usage, MAX_STOP_SEQUENCES,
+};
+use futures::stream::{BoxStream, Stream, StreamExt};
+use reqwest::Response;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// The author of a chat message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Instructions that set the behavior of the assistant.
+    System,
+    /// A message of the end user.
+    User,
+    /// A message generated by the model.
+    Assistant,
+    /// The result of a tool call requested by the model.
+    Tool,
+}
+
+/// A call to a tool requested by the model.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolCall {
+    /// The identifier of the call, to answer it with [`ChatMessage::tool`].
+    pub id: String,
+    /// The type of tool, always `function`.
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// The function to call.
+    pub function: FunctionCall,
+}
+
+/// The function and the arguments of a [`ToolCall`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    /// The name of the function.
+    pub name: String,
+    /// The arguments of the function, as a JSON object in a string.
+    pub arguments: String,
+}
+
+/// A tool the model can call, offered in [`ChatCompletionRequest::tools`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tool {
+    /// The type of tool, always `function`.
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// The function the model can call.
+    pub function: FunctionDefinition,
+}
+
+impl Tool {
+    /// Returns a function tool, whose arguments are described by the JSON schema `parameters`.
+    ///
+    /// ```
+    /// use davinci::Tool;
+    /// use serde_json::json;
+    ///
+    /// let tool = Tool::function(
+    ///     "get_weather",
+    ///     "Returns the weather of a city",
+    ///     json!({"type": "object", "properties": {"city": {"type": "string"}}, "required": ["city"]}),
+    /// );
+    /// assert_eq!(serde_json::to_value(&tool).unwrap()["type"], "function");
+    /// ```
+    pub fn function(
+        name: impl Into<String>,
+        description: impl Into<String>,
+        parameters: serde_json::Value,
+    ) -> Tool {
+        Tool {
+            kind: String::from("function"),
+            function: FunctionDefinition {
+                name: name.into(),
+                description: Some(description.into()),
+                parameters: Some(parameters),
+            },
+        }
+    }
+}
+
+/// The name, description and arguments of a function the model can call.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FunctionDefinition {
+    /// The name of the function.
+    pub name: String,
+    /// What the function does, so the model knows when to call it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// The JSON schema of the arguments of the function.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<serde_json::Value>,
+}
+
+/// Whether and which tool the model calls, set in [`ChatCompletionRequest::tool_choice`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "ToolChoiceJson", try_from = "ToolChoiceJson")]
+pub enum ToolChoice {
+    /// The model does not call any tool.
+    None,
+    /// The model decides whether to call tools. The default when tools are offered.
+    Auto,
+    /// The model calls at least one tool.
+    Required,
+    /// The model calls the function with this name.
+    Function(String),
+}
+
+/// A [`ToolChoice`] as the API sends it: a string, or an object naming a function.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum ToolChoiceJson {
+    Mode(String),
+    Function {
+        #[serde(rename = "type")]
+        kind: String,
+        function: FunctionName,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct FunctionName {
+    name: String,
+}
+
+impl From<ToolChoice> for ToolChoiceJson {
+    fn from(choice: ToolChoice) -> Self {
+        match choice {
+            ToolChoice::None => ToolChoiceJson::Mode(String::from("none")),
+            ToolChoice::Auto => ToolChoiceJson::Mode(String::from("auto")),
+            ToolChoice::Required => ToolChoiceJson::Mode(String::from("required")),
+            ToolChoice::Function(name) => ToolChoiceJson::Function {
+                kind: String::from("function"),
+                function: FunctionName { name },
+            },
+        }
+    }
+}
+
+impl TryFrom<ToolChoiceJson> for ToolChoice {
+    type Error = String;
+
+    fn try_from(choice: ToolChoiceJson) -> Result<Self, Self::Error> {
+        match choice {
+            ToolChoiceJson::Mode(mode) => match mode.as_str() {
+                "none" => Ok(ToolChoice::None),
+                "auto" => Ok(ToolChoice::Auto),
+                "required" => Ok(ToolChoice::Required),
+                _ => Err(format!("unknown tool choice {}", mode)),
+            },
+            ToolChoiceJson::Function { function, .. } => Ok(ToolChoice::Function(function.name)),
+        }
+    }
+}
+
+/// A message of a chat conversation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    /// The author of the message.
+    pub role: Role,
+    /// The text of the message. It is `None` in assistant messages that only call tools.
+    #[serde(default)]
+    pub content: Option<String>,
+    /// An optional name to tell apart participants with the same role.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The tool calls requested by the model, in assistant messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// The tool call this message answers, in tool messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    /// Returns a message with the given role and text.
+    pub fn new(role: Role, content: impl Into<String>) -> ChatMessage {
+        ChatMessage {
+            role,
+            content: Some(content.into()),
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    /// Returns a system message.
+    pub fn system(content: impl Into<String>) -> ChatMessage {
+        ChatMessage::new(Role::System, content)
+    }
+
+    /// Returns a user message.
+    pub fn user(content: impl Into<String>) -> ChatMessage {
+        ChatMessage::new(Role::User, content)
+    }
+
+    /// Returns an assistant message.
+    pub fn assistant(content: impl Into<String>) -> ChatMessage {
+        ChatMessage::new(Role::Assistant, content)
+    }
+
+    /// Returns the answer to the tool call `tool_call_id`.
+    pub fn tool(tool_call_id: impl Into<String>, content: impl Into<String>) -> ChatMessage {
+        ChatMessage {
+            tool_call_id: Some(tool_call_id.into()),
+            ..ChatMessage::new(Role::Tool, content)
+        }
+    }
+
+    /// Returns the text of the message, or an empty string if it does not have any.
+    pub fn text(&self) -> &str {
+        self.content.as_deref().unwrap_or_default()
+    }
+}
+
+/// The body of a request to the `/v1/chat/completions` endpoint.
+///
+/// Build one with [`ChatCompletionRequest::builder`], which checks the ranges of the parameters.
+/// The `None` fields are not serialized.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChatCompletionRequest {
+    /// The model that answers. When it is `None`, the client's default chat model is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// The conversation so far.
+    pub messages: Vec<ChatMessage>,
+    /// The maximum number of tokens to generate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    /// The sampling temperature, between `0.0` and `2.0`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    /// The nucleus sampling probability mass, between `0.0` and `1.0`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    /// How many answers to generate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
+    /// Whether to stream the answer back as it is generated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    /// The options of the stream, for a streamed request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
+    /// The sequences where the model stops generating.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Stop>,
+    /// The penalty for tokens that already appeared in the text, between `-2.0` and `2.0`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
+    /// The penalty for tokens based on how often they appeared in the text, between `-2.0` and `2.0`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+    /// A bias between `-100` and `100` added to the likelihood of the given token ids.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<BTreeMap<u32, i32>>,
+    /// A seed to make sampling as deterministic as the server allows.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    /// An identifier of the end user, to help the provider detect abuse.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// The tools the model can call.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<Tool>>,
+    /// Whether and which tool the model calls.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
+}
+
+impl ChatCompletionRequest {
+    /// Returns a builder to configure a new request.
+    pub fn builder() -> ChatCompletionRequestBuilder {
+        ChatCompletionRequestBuilder::default()
+    }
+
+    /// Returns a request for `messages` with every other parameter unset.
+    pub fn new(messages: Vec<ChatMessage>) -> ChatCompletionRequest {
+        ChatCompletionRequest {
+            messages,
+            ..ChatCompletionRequest::default()
+        }
+    }
+
+    /// Fills the parameters that are not set with the ones of the client's defaults
+    /// that also exist in chat completions.
+    pub(crate) fn merge_defaults(&mut self, defaults: &CompletionRequest) {
+        fill(&mut self.max_tokens, &defaults.max_tokens);
+        fill(&mut self.temperature, &defaults.temperature);
+        fill(&mut self.top_p, &defaults.top_p);
+        fill(&mut self.n, &defaults.n);
+        fill(&mut self.stop, &defaults.stop);
+        fill(&mut self.presence_penalty, &defaults.presence_penalty);
+        fill(&mut self.frequency_penalty, &defaults.frequency_penalty);
+        fill(&mut self.logit_bias, &defaults.logit_bias);
+        fill(&mut self.seed, &defaults.seed);
+        fill(&mut self.user, &defaults.user);
+    }
+
+    /// Returns `true` if the request asks for the most likely answer, with a temperature of `0`,
+    /// and is not streamed, so that sending it again is expected to give the same response.
+    pub fn is_deterministic(&self) -> bool {
+        self.temperature == Some(0.0) && self.stream != Some(true)
+    }
+
+    /// Checks that every parameter is in the range accepted by the API.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DavinciError::InvalidInput`] describing the first parameter out of range.
+    pub fn validate(&self) -> Result<(), DavinciError> {
+        if self.messages.is_empty() {
+            return Err(DavinciError::InvalidInput(String::from(
+                "a chat request must contain at least one message",
+            )));
+        }
+        if self.max_tokens == Some(0) {
+            return Err(DavinciError::InvalidInput(String::from(
+                "max_tokens must be positive",
+            )));
+        }
+        check_range("temperature", self.temperature, 0.0, 2.0)?;
+        check_range("top_p", self.top_p, 0.0, 1.0)?;
+        check_range("presence_penalty", self.presence_penalty, -2.0, 2.0)?;
+        check_range("frequency_penalty", self.frequency_penalty, -2.0, 2.0)?;
+        if self.n == Some(0) {
+            return Err(DavinciError::InvalidInput(String::from(
+                "n must be positive",
+            )));
+        }
+        if let Some(stop) = &self.stop {
+            if stop.len() > MAX_STOP_SEQUENCES {
+                return Err(DavinciError::InvalidInput(format!(
+                    "at most {} stop sequences are allowed, got {}",
+                    MAX_STOP_SEQUENCES,
+                    stop.len()
+                )));
+            }
+        }
+        let tools = self.tools.as_deref().unwrap_or_default();
+        match &self.tool_choice {
+            Some(ToolChoice::None) | None => {}
+            Some(_) if tools.is_empty() => {
+                return Err(DavinciError::InvalidInput(String::from(
+                    "tool_choice needs at least one tool",
+                )))
+            }
+            Some(ToolChoice::Function(name))
+                if !tools.iter().any(|tool| tool.function.name == *name) =>
+            {
+                return Err(DavinciError::InvalidInput(format!(
+                    "tool_choice names the function {}, which is not a tool of the request",
+                    name
+                )))
+            }
+            Some(_) => {}
+        }
+        Ok(())
+    }
+}
+
+/// A builder for [`ChatCompletionRequest`].
+#[derive(Debug, Clone, Default)]
+pub struct ChatCompletionRequestBuilder {
+    request: ChatCompletionRequest,
+}
+
+impl ChatCompletionRequestBuilder {
+    /// Sets the model that answers.
+    pub fn model(mut self, model: impl Into<String>) -> Self {
+        self.request.model = Some(model.into());
+        self
+    }
+
+    /// Appends a message to the conversation.
+    pub fn message(mut self, message: ChatMessage) -> Self {
+        self.request.messages.push(message);
+        self
+    }
+
+    /// Appends several messages to the conversation.
+    pub fn messages(mut self, messages: impl IntoIterator<Item = ChatMessage>) -> Self {
+        self.request.messages.extend(messages);
+        self
+    }
+
+    /// Sets the maximum number of tokens to generate.
+    pub fn max_tokens(mut self, max_tokens: u32) -> Self {
+        self.request.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Sets the sampling temperature, between `0.0` and `2.0`.
+    pub fn temperature(mut self, temperature: f64) -> Self {
+        self.request.temperature = Some(temperature);
+        self
+    }
+
+    /// Sets the nucleus sampling probability mass, between `0.0` and `1.0`.
+    pub fn top_p(mut self, top_p: f64) -> Self {
+        self.request.top_p = Some(top_p);
+        self
+    }
+
+    /// Sets how many answers to generate.
+    pub fn n(mut self, n: u32) -> Self {
+        self.request.n = Some(n);
+        self
+    }
+
+    /// Sets the sequences where the model stops generating.
+    pub fn stop<I, S>(mut self, stop: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.request.stop = Some(Stop::Many(stop.into_iter().map(Into::into).collect()));
+        self
+    }
+
+    /// Sets the penalty for tokens that already appeared in the text, between `-2.0` and `2.0`.
+    pub fn presence_penalty(mut self, presence_penalty: f64) -> Self {
+        self.request.presence_penalty = Some(presence_penalty);
+        self
+    }
+
+    /// Sets the penalty for tokens based on how often they appeared, between `-2.0` and `2.0`.
+    pub fn frequency_penalty(mut self, frequency_penalty: f64) -> Self {
+        self.request.frequency_penalty = Some(frequency_penalty);
+        self
+    }
+
+    /// Sets the bias added to the likelihood of a token id, between `-100` and `100`.
+    pub fn logit_bias(mut self, token: u32, bias: i32) -> Self {
+        self.request
+            .logit_bias
+            .get_or_insert_with(BTreeMap::new)
+            .insert(token, bias);
+        self
+    }
+
+    /// Sets the seed used for sampling.
+    pub fn seed(mut self, seed: i64) -> Self {
+        self.request.seed = Some(seed);
+        self
+    }
+
+    /// Sets the identifier of the end user.
+    pub fn user(mut self, user: impl Into<String>) -> Self {
+        self.request.user = Some(user.into());
+        self
+    }
+
+    /// Offers a tool to the model.
+    pub fn tool(mut self, tool: Tool) -> Self {
+        self.request.tools.get_or_insert_with(Vec::new).push(tool);
+        self
+    }
+
+    /// Offers several tools to the model.
+    pub fn tools(mut self, tools: impl IntoIterator<Item = Tool>) -> Self {
+        self.request
+            .tools
+            .get_or_insert_with(Vec::new)
+            .extend(tools);
+        self
+    }
+
+    /// Sets whether and which tool the model calls.
+    pub fn tool_choice(mut self, tool_choice: ToolChoice) -> Self {
+        self.request.tool_choice = Some(tool_choice);
+        self
+    }
+
+    /// Builds the request.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DavinciError::InvalidInput`] if there is no message,
+    /// a parameter is out of the range accepted by the API,
+    /// or the tool choice names a tool the request does not offer.
+    pub fn build(self) -> Result<ChatCompletionRequest, DavinciError> {
+        self.request.validate()?;
+        Ok(self.request)
+    }
+}
+
+/// One of the answers generated for a conversation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatChoice {
+    /// The position of the choice in the response.
+    pub index: u32,
+    /// The answer of the model.
+    pub message: ChatMessage,
+    /// Why the model stopped generating.
+    #[serde(default)]
+    pub finish_reason: Option<FinishReason>,
+}
+
+/// The response of the `/v1/chat/completions` endpoint.
+///
+/// ```
+/// use davinci::{ChatCompletionResponse, Role};
+///
+/// let response: ChatCompletionResponse = serde_json::from_str(r#"{
+///     "id": "chatcmpl-1", "object": "chat.completion", "created": 1677652288, "model": "gpt-4o-mini",
+///     "choices": [{"index": 0, "message": {"role": "assistant", "content": "Hello!"}, "finish_reason": "stop"}],
+///     "usage": {"prompt_tokens": 9, "completion_tokens": 2, "total_tokens": 11}
+/// }"#).unwrap();
+///
+/// assert_eq!(response.text(), Some("Hello!"));
+/// assert_eq!(response.message().unwrap().role, Role::Assistant);
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatCompletionResponse {
+    /// The unique identifier of the completion.
+    pub id: String,
+    /// The type of object, always `chat.completion`.
+    pub object: String,
+    /// The Unix timestamp, in seconds, of when the completion was created.
+    pub created: u64,
+    /// The model that generated the answer.
+    pub model: String,
+    /// The generated answers.
+    pub choices: Vec<ChatChoice>,
+    /// The token usage of the request. Some compatible servers do not send it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    /// The backend configuration the model ran with, if the server sends it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_fingerprint: Option<String>,
+}
+
+impl ChatCompletionResponse {
+    /// Returns the message of the first choice.
+    pub fn message(&self) -> Option<&ChatMessage> {
+        self.choices.first().map(|choice| &choice.message)
+    }
+
+    /// Returns the text of the first choice.
+    pub fn text(&self) -> Option<&str> {
+        self.message()
+            .and_then(|message| message.content.as_deref())
+    }
+
+    /// Returns why the model stopped generating the first choice.
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.choices.first().and_then(|choice| choice.finish_reason)
+    }
+
+    /// Returns the cost of the request in dollars, from its usage and the price of its model,
+    /// or `None` if the server did not send the usage or `pricing` does not know the model.
+    pub fn cost(&self, pricing: &PricingTable) -> Option<f64> {
+        Some(self.usage?.cost(pricing.price(&self.model)?))
+    }
+
+    /// Consumes the response and returns the message of the first choice.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DavinciError::EmptyChoices`] if the response does not contain any choice.
+    pub fn into_message(self) -> Result<ChatMessage, DavinciError> {
+        match self.choices.into_iter().next() {
+            Some(choice) => Ok(choice.message),
+            None => Err(DavinciError::EmptyChoices),
+        }
+    }
+
+    /// Consumes the response and returns the text of the first choice.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DavinciError::EmptyChoices`] if the response does not contain any choice.
+    pub fn into_text(self) -> Result<String, DavinciError> {
+        Ok(self.into_message()?.content.unwrap_or_default())
+    }
+}
+
+/// The part of a message sent in a [`ChatCompletionChunk`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChatDelta {
+    /// The author of the message, sent in the first chunk.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+    /// The new text of the message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    /// The new parts of the tool calls requested by the model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+/// The part of a [`ToolCall`] sent in a [`ChatDelta`].
+///
+/// The first part of a call has its id, type and function name,
+/// and the next ones the following pieces of its arguments.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolCallDelta {
+    /// The position of the call in the tool calls of the message.
+    pub index: u32,
+    /// The identifier of the call, in its first part.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The type of tool, in the first part of the call.
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    /// The new part of the function call.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub function: Option<FunctionCallDelta>,
+}
+
+/// The part of a [`FunctionCall`] sent in a [`ToolCallDelta`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FunctionCallDelta {
+    /// The name of the function, in the first part of the call.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The next piece of the arguments.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arguments: Option<String>,
+}
+
+/// A choice of a [`ChatCompletionChunk`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatChunkChoice {
+    /// The position of the choice in the response.
+    pub index: u32,
+    /// The new part of the message.
+    #[serde(default)]
+    pub delta: ChatDelta,
+    /// Why the model stopped generating, in the last chunk of the choice.
+    #[serde(default)]
+    pub finish_reason: Option<FinishReason>,
+}
+
+/// A part of a streamed chat completion.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatCompletionChunk {
+    /// The unique identifier of the completion, the same for every chunk.
+    pub id: String,
+    /// The type of object, always `chat.completion.chunk`.
+    pub object: String,
+    /// The Unix timestamp, in seconds, of when the completion was created.
+    pub created: u64,
+    /// The model that generates the answer.
+    pub model: String,
+    /// The new parts of the choices.
+    pub choices: Vec<ChatChunkChoice>,
+    /// The token usage, sent in a last chunk without choices when the request
+    /// sets [`StreamOptions::include_usage`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+impl ChatCompletionChunk {
+    /// Returns the new text of the first choice of the chunk.
+    pub fn text(&self) -> Option<&str> {
+        self.choices
+            .first()
+            .and_then(|choice| choice.delta.content.as_deref())
+    }
+}
+
+/// A stream of [`ChatCompletionChunk`]s, returned by [`crate::DavinciClient::stream_chat`].
+///
+/// It ends after the `[DONE]` event. An error event sent in the middle of the stream
+/// is returned as a [`DavinciError::Api`] item.
+pub struct ChatCompletionStream {
+    inner: BoxStream<'static, Result<ChatCompletionChunk, DavinciError>>,
+}
+
+impl fmt::Debug for ChatCompletionStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChatCompletionStream")
+            .finish_non_exhaustive()
+    }
+}
+
+impl ChatCompletionStream {
+    /// Wraps any stream of chunks, such as one built in a test.
+    pub fn new<S>(stream: S) -> ChatCompletionStream
+    where
+        S: Stream<Item = Result<ChatCompletionChunk, DavinciError>> + Send + 'static,
+    {
+        ChatCompletionStream {
+            inner: stream.boxed(),
+        }
+    }
+
+    /// Parses the server-sent events of a response, until `cancellation` is cancelled.
+    pub(crate) fn from_response(
+        response: Response,
+        cancellation: Option<CancellationToken>,
+    ) -> ChatCompletionStream {
+        ChatCompletionStream::new(until_cancelled(json_events(response), cancellation))
+    }
+
+    /// Reads the whole stream and joins the chunks into one response,
+    /// as if the request had not been streamed.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error of the stream, or [`DavinciError::EmptyChoices`]
+    /// if the stream ended without any choice.
+    pub async fn collect_response(mut self) -> Result<ChatCompletionResponse, DavinciError> {
+        let mut response: Option<ChatCompletionResponse> = None;
+        let mut choices: BTreeMap<u32, ChatChoice> = BTreeMap::new();
+        // The tool calls by the index of their choice and their own index.
+        let mut tool_calls: BTreeMap<(u32, u32), ToolCall> = BTreeMap::new();
+
+        while let Some(chunk) = self.next().await {
+            let chunk = chunk?;
+            let response = response.get_or_insert_with(|| ChatCompletionResponse {
+                id: chunk.id.clone(),
+                object: String::from("chat.completion"),
+                created: chunk.created,
+                model: chunk.model.clone(),
+                choices: Vec::new(),
+                usage: None,
+                system_fingerprint: None,
+            });
+            if chunk.usage.is_some() {
+                response.usage = chunk.usage;
+            }
+
+            for part in chunk.choices {
+                let choice = choices.entry(part.index).or_insert_with(|| ChatChoice {
+                    index: part.index,
+                    message: ChatMessage {
+                        content: None,
+                        ..ChatMessage::assistant("")
+                    },
+                    finish_reason: None,
+                });
+                if let Some(role) = part.delta.role {
+                    choice.message.role = role;
+                }
+                if let Some(content) = part.delta.content {
+                    choice
+                        .message
+                        .content
+                        .get_or_insert_with(String::new)
+                        .push_str(&content);
+                }
+                for delta in part.delta.tool_calls.unwrap_or_default() {
+                    merge_tool_call(&mut tool_calls, part.index, delta);
+                }
+                if part.finish_reason.is_some() {
+                    choice.finish_reason = part.finish_reason;
+                }
+            }
+        }
+
+        match response {
+            Some(mut response) if !choices.is_empty() => {
+                for ((index, _), call) in tool_calls {
+                    if let Some(choice) = choices.get_mut(&index) {
+                        choice
+                            .message
+                            .tool_calls
+                            .get_or_insert_with(Vec::new)
+                            .push(call);
+                    }
+                }
+                response.choices = choices.into_values().collect();
+                Ok(response)
+            }
+            _ => Err(DavinciError::EmptyChoices),
+        }
+    }
+
+    /// Reads the whole stream and returns the text of the first choice.
+    ///
+    /// # Errors
+    ///
+    /// See [`ChatCompletionStream::collect_response`].
+    pub async fn collect_text(self) -> Result<String, DavinciError> {
+        self.collect_response().await?.into_text()
+    }
+}
+
+/// Adds the part of a streamed tool call to the call of `choice` at its index.
+///
+/// The calls are kept in a map rather than at their position in a vector, so that an index
+/// sent by the server never makes room for the calls before it.
+fn merge_tool_call(calls: &mut BTreeMap<(u32, u32), ToolCall>, choice: u32, delta: ToolCallDelta) {
+    let call = calls
+        .entry((choice, delta.index))
+        .or_insert_with(|| ToolCall {
+            id: String::new(),
+            kind: String::from("function"),
+            function: FunctionCall {
+                name: String::new(),
+                arguments: String::new(),
+            },
+        });
+    if let Some(id) = delta.id {
+        call.id = id;
+    }
+    if let Some(kind) = delta.kind {
+        call.kind = kind;
+    }
+    if let Some(function) = delta.function {
+        if let Some(name) = function.name {
+            call.function.name.push_str(&name);
+        }
+        if let Some(arguments) = function.arguments {
+            call.function.arguments.push_str(&arguments);
+        }
+    }
+}
+
+impl Stream for ChatCompletionStream {
+    type Item = Result<ChatCompletionChunk, DavinciError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn chunk(tool_calls: serde_json::Value) -> Result<ChatCompletionChunk, DavinciError> {
+        Ok(serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": "gpt-3.5-turbo",
+            "choices": [{"index": 0, "delta": {"tool_calls": tool_calls}, "finish_reason": null}],
+        }))
+        .unwrap())
+    }
+
+    #[tokio::test]
+    async fn collect_response_joins_the_parts_of_the_tool_calls() {
+        let stream = ChatCompletionStream::new(futures::stream::iter(vec![
+            chunk(json!([{"index": 1, "id": "call_b", "type": "function",
+                "function": {"name": "second", "arguments": ""}}])),
+            chunk(json!([{"index": 0, "id": "call_a", "type": "function",
+                "function": {"name": "first", "arguments": "{\"a\":"}}])),
+            chunk(json!([{"index": 0, "function": {"arguments": "1}"}}])),
+        ]));
+
+        let response = stream.collect_response().await.unwrap();
+
+        let calls = response.choices[0].message.tool_calls.as_ref().unwrap();
+        let calls: Vec<(&str, &str, &str)> = calls
+            .iter()
+            .map(|call| {
+                (
+                    call.id.as_str(),
+                    call.function.name.as_str(),
+                    call.function.arguments.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            calls,
+            vec![("call_a", "first", "{\"a\":1}"), ("call_b", "second", "")]
+        );
+    }
+
+    #[tokio::test]
+    async fn collect_response_does_not_make_room_for_a_huge_tool_call_index() {
+        let stream = ChatCompletionStream::new(futures::stream::iter(vec![chunk(json!([{
+            "index": u32::MAX,
+            "id": "call_a",
+            "type": "function",
+            "function": {"name": "first", "arguments": "{}"},
+        }]))]));
+
+        let response = stream.collect_response().await.unwrap();
+
+        let calls = response.choices[0].message.tool_calls.as_ref().unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id, "call_a");
+    }
+}
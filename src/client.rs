@@ -0,0 +1,1900 @@
+//! A reusable client for the OpenAI API.
+//!
+//! [`DavinciClient`] keeps one connection pool, the api key, the endpoint and the default
+//! parameters of the requests, so they are not built again for every question.
+//! It is cheap to clone, and every clone shares the same connection pool,
+//! so it can be handed to as many tasks as needed.
+//!
+//! ```no_run
+//! use davinci::DavinciClient;
+//! use std::time::Duration;
+//!
+//! # async fn run() -> Result<(), davinci::DavinciError> {
+//! let client = DavinciClient::builder()
+//!     .api_key("vj-JZkjskhdksKXOlncknjckukNKKnkJNKJNkNKNk")
+//!     .temperature(0.2)
+//!     .max_tokens(256)
+//!     .timeout(Duration::from_secs(30))
+//!     .build()?;
+//!
+//! let response = client
+//!     .ask("The assistant is helpful, creative, clever, and very friendly", "Hello, who are you?", 100)
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::budget::{charge_stream, Reservation};
+use crate::fan_out::fan_out;
+use crate::retry::{server_delay, RetryHook};
+use crate::tokenizer::Encoding;
+use crate::{
+    Budget, BudgetLimit, CancellationToken, Cassette, ChatCompletionChunk, ChatCompletionRequest,
+    ChatCompletionResponse, ChatCompletionStream, Choice, CompletionChunk, CompletionRequest,
+    CompletionResponse, CompletionStream, ContextWindowPolicy, Conversation, DavinciError,
+    ModelPrice, ModelRegistry, PricingTable, Prompt, PromptTemplate, RateLimiter, ResponseCache,
+    RetryAttempt, RetryPolicy, RunOptions, Stop, StreamOptions, TruncationStrategy, Usage,
+    MAX_BATCH_PROMPTS,
+};
+use futures::stream::{BoxStream, Stream, StreamExt};
+use reqwest::{Client, Response};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+/// The default server of the OpenAI API.
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com";
+
+/// The default prefix of the paths of the API.
+pub const DEFAULT_PATH_PREFIX: &str = "/v1";
+
+/// The environment variable read for the base URL when none is given to the builder.
+pub const BASE_URL_ENV: &str = "OPENAI_BASE_URL";
+
+/// The model used by completions when none is given to the builder.
+pub const DEFAULT_MODEL: &str = "text-davinci-003";
+
+/// The model used by chat completions when none is given to the builder.
+pub const DEFAULT_CHAT_MODEL: &str = "gpt-4o-mini";
+
+/// The maximum time a request can take when no timeout is given to the builder.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// The maximum time to open a connection when no connect timeout is given to the builder.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The number of tokens the API generates for a completion that does not set `max_tokens`.
+const COMPLETION_MAX_TOKENS: u32 = 16;
+
+/// The number of tokens a chat answer without `max_tokens` is expected to use, for its cost.
+const CHAT_EXPECTED_MAX_TOKENS: u32 = 1024;
+
+/// A client for the OpenAI API.
+///
+/// Build one with [`DavinciClient::builder`]. Cloning it is cheap: every clone shares
+/// the same configuration and connection pool.
+///
+/// [`DavinciClient::with_timeout`] and [`DavinciClient::with_cancellation`] return a copy
+/// whose requests have their own timeout or can be cancelled, for one request or a few.
+#[derive(Clone)]
+pub struct DavinciClient {
+    inner: Arc<ClientInner>,
+    options: RequestOptions,
+}
+
+/// The settings of a copy of the client that override the ones of its builder.
+#[derive(Debug, Clone, Default)]
+struct RequestOptions {
+    http: Option<Client>,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    cancellation: Option<CancellationToken>,
+    bypass_cache: bool,
+}
+
+struct ClientInner {
+    http: Client,
+    custom_http: bool,
+    api_key: String,
+    base_url: String,
+    path_prefix: String,
+    model: String,
+    chat_model: String,
+    defaults: CompletionRequest,
+    template: PromptTemplate,
+    models: ModelRegistry,
+    context_window_policy: ContextWindowPolicy,
+    truncation: TruncationStrategy,
+    retry: RetryPolicy,
+    on_retry: Option<RetryHook>,
+    rate_limiter: Option<RateLimiter>,
+    cache: Option<ResponseCache>,
+    cassette: Option<Cassette>,
+    max_batch_prompts: usize,
+    pricing: PricingTable,
+    budget: Option<Budget>,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+}
+
+impl fmt::Debug for DavinciClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DavinciClient")
+            .field("api_key", &"<redacted>")
+            .field("base_url", &self.inner.base_url)
+            .field("path_prefix", &self.inner.path_prefix)
+            .field("model", &self.inner.model)
+            .field("chat_model", &self.inner.chat_model)
+            .field("defaults", &self.inner.defaults)
+            .field("context_window_policy", &self.inner.context_window_policy)
+            .field("truncation", &self.inner.truncation)
+            .field("retry", &self.inner.retry)
+            .field("rate_limiter", &self.inner.rate_limiter)
+            .field("cache", &self.cache())
+            .field("cassette", &self.inner.cassette)
+            .field("max_batch_prompts", &self.inner.max_batch_prompts)
+            .field("budget", &self.inner.budget)
+            .field("timeout", &self.timeout())
+            .field("connect_timeout", &self.connect_timeout())
+            .field("cancellation", &self.options.cancellation)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Returns the connection pool shared by the clients that were not given one,
+/// with the [`DEFAULT_CONNECT_TIMEOUT`].
+fn shared_http_client() -> Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT
+        .get_or_init(|| {
+            Client::builder()
+                .connect_timeout(DEFAULT_CONNECT_TIMEOUT)
+                .build()
+                .unwrap_or_default()
+        })
+        .clone()
+}
+
+impl DavinciClient {
+    /// Returns a builder to configure a new client.
+    pub fn builder() -> DavinciClientBuilder {
+        DavinciClientBuilder::default()
+    }
+
+    /// Returns a client with the default configuration and the given api key.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DavinciError::InvalidInput`] if `api_key` is empty.
+    pub fn new(api_key: impl Into<String>) -> Result<DavinciClient, DavinciError> {
+        DavinciClient::builder().api_key(api_key).build()
+    }
+
+    /// Returns the base URL the requests are sent to.
+    pub fn base_url(&self) -> &str {
+        &self.inner.base_url
+    }
+
+    /// Returns the prefix added between the base URL and the path of every endpoint.
+    pub fn path_prefix(&self) -> &str {
+        &self.inner.path_prefix
+    }
+
+    /// Returns the full URL of an endpoint of the API, such as `/completions`.
+    ///
+    /// ```
+    /// use davinci::DavinciClient;
+    ///
+    /// let client = DavinciClient::builder()
+    ///     .api_key("sk-local")
+    ///     .base_url("http://localhost:8080/v1")
+    ///     .build()
+    ///     .unwrap();
+    /// assert_eq!(client.endpoint("/completions"), "http://localhost:8080/v1/completions");
+    ///
+    /// let client = DavinciClient::builder()
+    ///     .api_key("sk-local")
+    ///     .base_url("http://localhost:8080")
+    ///     .path_prefix("")
+    ///     .build()
+    ///     .unwrap();
+    /// assert_eq!(client.endpoint("/completions"), "http://localhost:8080/completions");
+    /// ```
+    pub fn endpoint(&self, path: &str) -> String {
+        format!("{}{}{}", self.inner.base_url, self.inner.path_prefix, path)
+    }
+
+    /// Returns the model used by the completion requests.
+    pub fn model(&self) -> &str {
+        &self.inner.model
+    }
+
+    /// Returns the model used by the chat completion requests.
+    pub fn chat_model(&self) -> &str {
+        &self.inner.chat_model
+    }
+
+    /// Returns the parameters applied to the requests that do not set them.
+    pub fn defaults(&self) -> &CompletionRequest {
+        &self.inner.defaults
+    }
+
+    /// Returns the template that renders the prompt of [`DavinciClient::ask`],
+    /// and of [`DavinciClient::ask_conversation`] for the conversations with the default one.
+    pub fn prompt_template(&self) -> &PromptTemplate {
+        &self.inner.template
+    }
+
+    /// Returns a conversation without any turn, rendered with the client's [`PromptTemplate`].
+    ///
+    /// ```
+    /// use davinci::{DavinciClient, PromptTemplate};
+    ///
+    /// # fn run() -> Result<(), davinci::DavinciError> {
+    /// let client = DavinciClient::builder()
+    ///     .api_key("vj-JZkjskhdksKXOlncknjckukNKKnkJNKJNkNKNk")
+    ///     .prompt_template(PromptTemplate::question_answer())
+    ///     .build()?;
+    ///
+    /// let conversation = client.conversation("You are a poet");
+    /// assert_eq!(conversation.template(), &PromptTemplate::question_answer());
+    /// # Ok(())
+    /// # }
+    /// # run().unwrap();
+    /// ```
+    pub fn conversation(&self, context: impl Into<String>) -> Conversation {
+        Conversation::with_template(context, self.inner.template.clone())
+    }
+
+    /// Returns the registry of the context lengths of the models.
+    pub fn models(&self) -> &ModelRegistry {
+        &self.inner.models
+    }
+
+    /// Returns what the client does with a request that does not fit
+    /// in the context window of its model.
+    pub fn context_window_policy(&self) -> ContextWindowPolicy {
+        self.inner.context_window_policy
+    }
+
+    /// Returns how the transcript of a conversation too long for the context window is shortened.
+    pub fn truncation(&self) -> TruncationStrategy {
+        self.inner.truncation
+    }
+
+    /// Returns how the failed requests are retried.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.inner.retry
+    }
+
+    /// Returns the maximum time a request can take, if it is limited.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.options.timeout.or(self.inner.timeout)
+    }
+
+    /// Returns the maximum time to open a connection,
+    /// or `None` if it is set by a custom `reqwest::Client`.
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.options.connect_timeout.or(self.inner.connect_timeout)
+    }
+
+    /// Returns the token that cancels the requests of this copy of the client, if it has one.
+    pub fn cancellation(&self) -> Option<&CancellationToken> {
+        self.options.cancellation.as_ref()
+    }
+
+    /// Returns a copy of the client whose requests can take at most `timeout`,
+    /// instead of the timeout of the builder.
+    ///
+    /// ```no_run
+    /// use davinci::DavinciClient;
+    /// use std::time::Duration;
+    ///
+    /// # async fn run(client: DavinciClient) -> Result<(), davinci::DavinciError> {
+    /// let answer = client
+    ///     .with_timeout(Duration::from_secs(5))
+    ///     .ask("You are a quick assistant", "Say hello", 10)
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_timeout(&self, timeout: Duration) -> DavinciClient {
+        let mut client = self.clone();
+        client.options.timeout = Some(timeout);
+        client
+    }
+
+    /// Returns a copy of the client that opens its connections within `connect_timeout`,
+    /// instead of the connect timeout of the builder.
+    ///
+    /// The copy and its clones have their own connection pool, so it is meant to be reused
+    /// for many requests rather than built for each one. It is the same client when
+    /// a custom client was given with [`DavinciClientBuilder::http_client`],
+    /// as the connect timeout belongs to the connection pool.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DavinciError::Transport`] if the connection pool could not be built.
+    pub fn with_connect_timeout(
+        &self,
+        connect_timeout: Duration,
+    ) -> Result<DavinciClient, DavinciError> {
+        let mut client = self.clone();
+        if !self.inner.custom_http {
+            client.options.http = Some(Client::builder().connect_timeout(connect_timeout).build()?);
+            client.options.connect_timeout = Some(connect_timeout);
+        }
+        Ok(client)
+    }
+
+    /// Returns a copy of the client whose requests stop as soon as `cancellation` is cancelled,
+    /// with [`DavinciError::Cancelled`].
+    ///
+    /// It stops the requests waiting for the rate limiter or a retry, the ones in flight,
+    /// and the streams, which end with a [`DavinciError::Cancelled`] item.
+    ///
+    /// ```no_run
+    /// use davinci::{CancellationToken, DavinciClient, DavinciError};
+    ///
+    /// # async fn run(client: DavinciClient) {
+    /// let cancellation = CancellationToken::new();
+    /// let client = client.with_cancellation(cancellation.clone());
+    ///
+    /// let answer = tokio::spawn(async move { client.ask("You are a poet", "Write an epic", 2000).await });
+    /// cancellation.cancel();
+    ///
+    /// assert!(matches!(answer.await.unwrap(), Err(DavinciError::Cancelled)));
+    /// # }
+    /// ```
+    pub fn with_cancellation(&self, cancellation: CancellationToken) -> DavinciClient {
+        let mut client = self.clone();
+        client.options.cancellation = Some(cancellation);
+        client
+    }
+
+    /// Returns a copy of the client whose requests are always sent to the API,
+    /// and whose responses are not stored in the cache.
+    pub fn without_cache(&self) -> DavinciClient {
+        let mut client = self.clone();
+        client.options.bypass_cache = true;
+        client
+    }
+
+    /// Returns the cache of the responses, unless this copy of the client bypasses it.
+    pub fn cache(&self) -> Option<&ResponseCache> {
+        if self.options.bypass_cache {
+            return None;
+        }
+        self.inner.cache.as_ref()
+    }
+
+    /// Returns the cache if it stores the responses of a request that is deterministic or not.
+    fn cache_for(&self, deterministic: bool) -> Option<&ResponseCache> {
+        self.cache().filter(|cache| cache.accepts(deterministic))
+    }
+
+    /// Returns the limiter of requests and tokens per minute, if the client has one.
+    pub fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.inner.rate_limiter.as_ref()
+    }
+
+    /// Returns the prices of the models, used by [`DavinciClient::estimate_cost`].
+    pub fn pricing(&self) -> &PricingTable {
+        &self.inner.pricing
+    }
+
+    /// Returns the most a completion request can cost, in dollars, before sending it.
+    ///
+    /// The prompt tokens are counted with the tokenizer of the model, and the answer is counted
+    /// as `max_tokens` (16 if it is not set) for every choice of every prompt, at the prices of
+    /// [`DavinciClient::pricing`]. The client's defaults and context window policy are applied
+    /// first, as when the request is sent.
+    ///
+    /// ```
+    /// use davinci::{CompletionRequest, DavinciClient};
+    ///
+    /// # fn run() -> Result<(), davinci::DavinciError> {
+    /// let client = DavinciClient::builder()
+    ///     .api_key("vj-JZkjskhdksKXOlncknjckukNKKnkJNKJNkNKNk")
+    ///     .model("gpt-3.5-turbo-instruct")
+    ///     .build()?;
+    /// let request = CompletionRequest::builder().prompt("Say this is a test").max_tokens(100).build()?;
+    ///
+    /// // 5 prompt tokens at $1.50 and 100 answer tokens at $2.00 per million tokens.
+    /// assert!((client.estimate_cost(&request)? - 0.0002075).abs() < 1e-12);
+    /// # Ok(())
+    /// # }
+    /// # run().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// * [`DavinciError::InvalidInput`] if the request is not valid, or the pricing
+    ///   does not know its model.
+    /// * [`DavinciError::ContextLengthExceeded`] if the request does not fit
+    ///   in the context window of its model.
+    pub fn estimate_cost(&self, request: &CompletionRequest) -> Result<f64, DavinciError> {
+        let request = self.prepare(request)?;
+        let (prompt_tokens, completion_tokens) = self.completion_tokens(&request);
+        let price = self.price(request.model.as_deref().unwrap_or_default())?;
+        Ok(price.cost(prompt_tokens, 0, completion_tokens))
+    }
+
+    /// Returns the most a chat completion request can cost, in dollars, before sending it.
+    ///
+    /// The answer is counted as `max_tokens` for every choice. A request that does not set it,
+    /// nor the client's defaults, is counted as 1,024 tokens for every choice, or the rest of
+    /// the context window of its model if that is less: its answer may cost more than that.
+    ///
+    /// # Errors
+    ///
+    /// See [`DavinciClient::estimate_cost`].
+    pub fn estimate_chat_cost(&self, request: &ChatCompletionRequest) -> Result<f64, DavinciError> {
+        let request = self.prepare_chat(request)?;
+        let (prompt_tokens, completion_tokens) = self.chat_budget_tokens(&request);
+        let price = self.price(request.model.as_deref().unwrap_or_default())?;
+        Ok(price.cost(prompt_tokens, 0, completion_tokens))
+    }
+
+    /// Returns the budget of the client, if it has one.
+    pub fn budget(&self) -> Option<&Budget> {
+        self.inner.budget.as_ref()
+    }
+
+    fn price(&self, model: &str) -> Result<&ModelPrice, DavinciError> {
+        self.inner.pricing.price(model).ok_or_else(|| {
+            DavinciError::InvalidInput(format!("the price of the model {} is not known", model))
+        })
+    }
+
+    /// Returns the maximum number of prompts sent in one completion request by
+    /// [`DavinciClient::complete_batch`].
+    pub fn max_batch_prompts(&self) -> usize {
+        self.inner.max_batch_prompts
+    }
+
+    /// Asks a question to the model and returns the text of its answer.
+    ///
+    /// The context and the question are rendered with the client's [`PromptTemplate`].
+    /// The parameters that are not set in the client's defaults keep the values
+    /// `davinci` has always used, a temperature of `0.9` and a presence penalty of `0.6`,
+    /// and the model stops where the template says the next question starts.
+    ///
+    /// # Parameters
+    ///
+    /// * `context` - The context for the question.
+    /// * `question` - The question or phrase to ask the model.
+    /// * `tokens` - The maximum number of tokens to use in the response.
+    ///
+    /// # Errors
+    ///
+    /// See [`crate::davinci`].
+    pub async fn ask(
+        &self,
+        context: &str,
+        question: &str,
+        tokens: i32,
+    ) -> Result<String, DavinciError> {
+        let conversation = Conversation::with_template(context, self.inner.template.clone());
+        let request = self.question_request(&conversation, question, tokens)?;
+
+        self.complete(&request).await
+    }
+
+    /// Asks a question after the transcript of a conversation, with a completion request,
+    /// and appends the question and the answer to the conversation.
+    ///
+    /// The transcript is rendered with the conversation's [`PromptTemplate`], or with the
+    /// client's if the conversation has the default one, as one made by [`Conversation::new`]
+    /// or deserialized does.
+    ///
+    /// If the transcript is too long for the context window of the model, only the turns kept
+    /// by the client's [`TruncationStrategy`] are sent, with `tokens` left for the answer.
+    /// The conversation is left unchanged if the request fails.
+    ///
+    /// # Parameters
+    ///
+    /// * `conversation` - The conversation the question belongs to.
+    /// * `question` - The question or phrase to ask the model.
+    /// * `tokens` - The maximum number of tokens to use in the response.
+    ///
+    /// # Errors
+    ///
+    /// See [`crate::davinci`].
+    pub async fn ask_conversation(
+        &self,
+        conversation: &mut Conversation,
+        question: &str,
+        tokens: i32,
+    ) -> Result<String, DavinciError> {
+        let request = if conversation.template() == &PromptTemplate::default() {
+            let mut seeded = conversation.clone();
+            seeded.set_template(self.inner.template.clone());
+            self.question_request(&seeded, question, tokens)?
+        } else {
+            self.question_request(conversation, question, tokens)?
+        };
+
+        let answer = self.complete(&request).await?;
+        conversation.push_turn(question, &answer);
+        Ok(answer)
+    }
+
+    /// Asks a question after the transcript of a conversation, with a chat completion request,
+    /// and appends the question and the answer to the conversation.
+    ///
+    /// The transcript is truncated like in [`DavinciClient::ask_conversation`].
+    /// The conversation is left unchanged if the request fails.
+    ///
+    /// # Errors
+    ///
+    /// See [`crate::davinci`].
+    pub async fn chat_conversation(
+        &self,
+        conversation: &mut Conversation,
+        question: &str,
+        tokens: i32,
+    ) -> Result<String, DavinciError> {
+        let max_tokens = check_tokens(tokens)?;
+        let kept = self.truncate(
+            conversation,
+            &self.inner.chat_model,
+            max_tokens,
+            |kept, encoding| encoding.count_messages(&kept.messages(question)),
+        );
+        let mut request = ChatCompletionRequest::new(kept.messages(question));
+        request.max_tokens = Some(max_tokens);
+
+        let answer = self.chat(&request).await?;
+        conversation.push_turn(question, &answer);
+        Ok(answer)
+    }
+
+    /// Builds the completion request of a question, with the parameters `davinci` has always used.
+    fn question_request(
+        &self,
+        conversation: &Conversation,
+        question: &str,
+        tokens: i32,
+    ) -> Result<CompletionRequest, DavinciError> {
+        let max_tokens = check_tokens(tokens)?;
+        let mut request = CompletionRequest {
+            max_tokens: Some(max_tokens),
+            ..CompletionRequest::default()
+        };
+        request.merge_defaults(&self.inner.defaults);
+        request.merge_defaults(&CompletionRequest {
+            temperature: Some(0.9),
+            top_p: Some(1.0),
+            frequency_penalty: Some(0.0),
+            presence_penalty: Some(0.6),
+            stop: Some(Stop::Many(conversation.template().stop().to_vec())),
+            ..CompletionRequest::default()
+        });
+
+        let model = request.model.as_deref().unwrap_or(&self.inner.model);
+        let kept = self.truncate(conversation, model, max_tokens, |kept, encoding| {
+            encoding.count(&kept.prompt(question))
+        });
+        request.prompt = Prompt::Text(kept.prompt(question));
+        Ok(request)
+    }
+
+    /// Drops turns of a conversation, as the client's [`TruncationStrategy`] says,
+    /// until its prompt plus `max_tokens` fits in the context window of `model`.
+    fn truncate<F>(
+        &self,
+        conversation: &Conversation,
+        model: &str,
+        max_tokens: u32,
+        prompt_tokens: F,
+    ) -> Conversation
+    where
+        F: Fn(&Conversation, Encoding) -> usize,
+    {
+        let encoding = Encoding::for_model_or_default(model);
+        let context_length = self.inner.models.context_length(model);
+
+        conversation.truncated(self.inner.truncation, |kept| match context_length {
+            Some(context_length) => {
+                prompt_tokens(kept, encoding) + max_tokens as usize <= context_length as usize
+            }
+            None => true,
+        })
+    }
+
+    /// Sends a completion request and returns the text of the first choice.
+    ///
+    /// The parameters that are not set in `request` are taken from the client's defaults,
+    /// and the client's model is used if the request does not name one.
+    ///
+    /// # Errors
+    ///
+    /// * [`DavinciError::InvalidInput`] if a parameter is out of the range accepted by the API.
+    /// * [`DavinciError::ContextLengthExceeded`] if the prompt plus `max_tokens` does not fit
+    ///   in the context window of the model, see [`ContextWindowPolicy`].
+    /// * [`DavinciError::BudgetExceeded`] if the request could cost more than what is left
+    ///   of the client's [`Budget`].
+    /// * [`DavinciError::Transport`] if the request could not be sent.
+    /// * [`DavinciError::Timeout`] if the connection or the request took longer than the timeouts.
+    /// * [`DavinciError::Cancelled`] if the client's cancellation token was cancelled.
+    /// * [`DavinciError::Api`] or [`DavinciError::Status`] if the server rejected the request.
+    /// * [`DavinciError::Deserialize`] if the response could not be parsed.
+    /// * [`DavinciError::EmptyChoices`] if the response did not contain any choice.
+    pub async fn complete(&self, request: &CompletionRequest) -> Result<String, DavinciError> {
+        self.create_completion(request).await?.into_text()
+    }
+
+    /// Sends a completion request and returns the whole response,
+    /// with its id, model, usage and every choice.
+    ///
+    /// The parameters that are not set in `request` are taken from the client's defaults,
+    /// and the client's model is used if the request does not name one.
+    ///
+    /// # Errors
+    ///
+    /// See [`DavinciClient::complete`].
+    pub async fn create_completion(
+        &self,
+        request: &CompletionRequest,
+    ) -> Result<CompletionResponse, DavinciError> {
+        let request = self.prepare(request)?;
+
+        let cache = self.cache_for(request.is_deterministic());
+        let endpoint = self.endpoint("/completions");
+        if let Some(response) = cache.and_then(|cache| cache.get(&endpoint, &request)) {
+            return Ok(response);
+        }
+
+        let model = request.model.as_deref().unwrap_or_default();
+        let reservation = self.reserve_budget(model, || self.completion_tokens(&request))?;
+        let tokens = self.completion_cost(&request);
+        let response: CompletionResponse = self.post("/completions", &request, tokens).await?;
+        self.settle(tokens, response.usage.as_ref());
+        if let Some(reservation) = reservation {
+            reservation.settle(response.usage.as_ref());
+        }
+
+        if response.choices.is_empty() {
+            return Err(DavinciError::EmptyChoices);
+        }
+        if let Some(cache) = cache {
+            cache.put(&endpoint, &request, &response);
+        }
+        Ok(response)
+    }
+
+    /// Sends a completion request with `stream: true` and returns the chunks as they arrive.
+    ///
+    /// The parameters that are not set in `request` are taken from the client's defaults,
+    /// and the client's model is used if the request does not name one.
+    /// The request sets [`StreamOptions::include_usage`], so the last chunk has the usage.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as [`DavinciClient::complete`] if the request is rejected.
+    /// Errors that happen once the stream has started are items of the stream.
+    pub async fn stream_completion(
+        &self,
+        request: &CompletionRequest,
+    ) -> Result<CompletionStream, DavinciError> {
+        let mut request = self.prepare(request)?;
+        request.stream = Some(true);
+        StreamOptions::include_usage(&mut request.stream_options);
+
+        let model = request.model.as_deref().unwrap_or_default();
+        let reservation = self.reserve_budget(model, || self.completion_tokens(&request))?;
+        let tokens = self.completion_cost(&request);
+        let response = self
+            .cancellable(self.send("/completions", &request, tokens))
+            .await?;
+
+        let usage: fn(&CompletionChunk) -> Option<&Usage> = |chunk| chunk.usage.as_ref();
+        let stream = CompletionStream::from_response(response, self.options.cancellation.clone());
+        let stream = self.settle_stream(stream, tokens, usage);
+        Ok(CompletionStream::new(match reservation {
+            Some(reservation) => charge_stream(stream, reservation, usage),
+            None => stream,
+        }))
+    }
+
+    /// Completes every prompt and returns the text of its first choice, in the order of the prompts.
+    ///
+    /// The prompts are sent as batches of [`DavinciClient::max_batch_prompts`] in one request each,
+    /// with the client's defaults and model. An empty list is answered without any request.
+    ///
+    /// ```no_run
+    /// # async fn run() -> Result<(), davinci::DavinciError> {
+    /// let client = davinci::DavinciClient::new("vj-JZkjskhdksKXOlncknjckukNKKnkJNKJNkNKNk")?;
+    ///
+    /// let answers = client
+    ///     .complete_batch(["Translate 'cat' to French:", "Translate 'dog' to French:"])
+    ///     .await?;
+    /// assert_eq!(answers.len(), 2);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error of the requests, as [`DavinciClient::complete`] does,
+    /// or [`DavinciError::EmptyChoices`] if a prompt did not get any choice.
+    pub async fn complete_batch<I>(&self, prompts: I) -> Result<Vec<String>, DavinciError>
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let prompts: Vec<String> = prompts.into_iter().map(Into::into).collect();
+        self.create_completion_batch(&CompletionRequest::new(prompts))
+            .await?
+            .into_iter()
+            .map(|choices| match choices.into_iter().next() {
+                Some(choice) => Ok(choice.text),
+                None => Err(DavinciError::EmptyChoices),
+            })
+            .collect()
+    }
+
+    /// Sends a request whose prompt is a batch, split into several requests if it has more than
+    /// [`DavinciClient::max_batch_prompts`] texts, and returns the choices of every prompt,
+    /// in the order of the prompts.
+    ///
+    /// The API numbers the `n` choices of the prompt `i` from `i * n`. They are given back
+    /// to their prompt and numbered from `0`, as if the prompt had been sent alone.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error of the requests, as [`DavinciClient::complete`] does.
+    /// The choices of the batches already answered are lost.
+    pub async fn create_completion_batch(
+        &self,
+        request: &CompletionRequest,
+    ) -> Result<Vec<Vec<Choice>>, DavinciError> {
+        let prompts = match &request.prompt {
+            Prompt::Text(prompt) => std::slice::from_ref(prompt),
+            Prompt::Batch(prompts) => prompts.as_slice(),
+        };
+        let n = request.n.or(self.inner.defaults.n).unwrap_or(1).max(1);
+
+        let mut results = Vec::with_capacity(prompts.len());
+        for batch in prompts.chunks(self.inner.max_batch_prompts) {
+            let mut request = request.clone();
+            request.prompt = Prompt::Batch(batch.to_vec());
+            let response = self.create_completion(&request).await?;
+
+            let mut choices = vec![Vec::new(); batch.len()];
+            for mut choice in response.choices {
+                if let Some(prompt) = choices.get_mut((choice.index / n) as usize) {
+                    choice.index %= n;
+                    prompt.push(choice);
+                }
+            }
+            for prompt in &mut choices {
+                prompt.sort_by_key(|choice| choice.index);
+            }
+            results.extend(choices);
+        }
+        Ok(results)
+    }
+
+    /// Sends every completion request, with at most [`RunOptions::concurrency`] in flight,
+    /// and returns a stream of the responses with the position of their request.
+    ///
+    /// The requests are taken from the iterator only when there is room for them.
+    /// Each one is sent as [`DavinciClient::create_completion`] does, with the retries,
+    /// rate limiter and cache of the client. A failed request does not stop the others.
+    /// Dropping the stream cancels the requests in flight.
+    ///
+    /// See [`RunOptions`] for the order of the results and the progress callback.
+    pub fn run_many<I>(
+        &self,
+        requests: I,
+        options: RunOptions,
+    ) -> BoxStream<'static, (usize, Result<CompletionResponse, DavinciError>)>
+    where
+        I: IntoIterator<Item = CompletionRequest>,
+        I::IntoIter: Send + 'static,
+    {
+        let client = self.clone();
+        fan_out(requests, options, move |request| {
+            let client = client.clone();
+            async move { client.create_completion(&request).await }
+        })
+    }
+
+    /// Sends every chat completion request, with at most [`RunOptions::concurrency`] in flight,
+    /// and returns a stream of the responses with the position of their request.
+    ///
+    /// See [`DavinciClient::run_many`].
+    pub fn run_many_chat<I>(
+        &self,
+        requests: I,
+        options: RunOptions,
+    ) -> BoxStream<'static, (usize, Result<ChatCompletionResponse, DavinciError>)>
+    where
+        I: IntoIterator<Item = ChatCompletionRequest>,
+        I::IntoIter: Send + 'static,
+    {
+        let client = self.clone();
+        fan_out(requests, options, move |request| {
+            let client = client.clone();
+            async move { client.create_chat_completion(&request).await }
+        })
+    }
+
+    /// Sends a chat completion request and returns the text of the first answer.
+    ///
+    /// The parameters that are not set in `request` are taken from the client's defaults,
+    /// and the client's chat model is used if the request does not name one.
+    ///
+    /// # Errors
+    ///
+    /// See [`DavinciClient::complete`].
+    pub async fn chat(&self, request: &ChatCompletionRequest) -> Result<String, DavinciError> {
+        self.create_chat_completion(request).await?.into_text()
+    }
+
+    /// Sends a chat completion request and returns the whole response.
+    ///
+    /// # Errors
+    ///
+    /// See [`DavinciClient::complete`].
+    pub async fn create_chat_completion(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<ChatCompletionResponse, DavinciError> {
+        let request = self.prepare_chat(request)?;
+
+        let cache = self.cache_for(request.is_deterministic());
+        let endpoint = self.endpoint("/chat/completions");
+        if let Some(response) = cache.and_then(|cache| cache.get(&endpoint, &request)) {
+            return Ok(response);
+        }
+
+        let model = request.model.as_deref().unwrap_or_default();
+        let reservation = self.reserve_budget(model, || self.chat_budget_tokens(&request))?;
+        let tokens = self.chat_cost(&request);
+        let response: ChatCompletionResponse =
+            self.post("/chat/completions", &request, tokens).await?;
+        self.settle(tokens, response.usage.as_ref());
+        if let Some(reservation) = reservation {
+            reservation.settle(response.usage.as_ref());
+        }
+
+        if response.choices.is_empty() {
+            return Err(DavinciError::EmptyChoices);
+        }
+        if let Some(cache) = cache {
+            cache.put(&endpoint, &request, &response);
+        }
+        Ok(response)
+    }
+
+    /// Sends a chat completion request with `stream: true` and returns the chunks as they arrive.
+    /// The request sets [`StreamOptions::include_usage`], so the last chunk has the usage.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as [`DavinciClient::complete`] if the request is rejected.
+    /// Errors that happen once the stream has started are items of the stream.
+    pub async fn stream_chat(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<ChatCompletionStream, DavinciError> {
+        let mut request = self.prepare_chat(request)?;
+        request.stream = Some(true);
+        StreamOptions::include_usage(&mut request.stream_options);
+
+        let model = request.model.as_deref().unwrap_or_default();
+        let reservation = self.reserve_budget(model, || self.chat_budget_tokens(&request))?;
+        let tokens = self.chat_cost(&request);
+        let response = self
+            .cancellable(self.send("/chat/completions", &request, tokens))
+            .await?;
+
+        let usage: fn(&ChatCompletionChunk) -> Option<&Usage> = |chunk| chunk.usage.as_ref();
+        let stream =
+            ChatCompletionStream::from_response(response, self.options.cancellation.clone());
+        let stream = self.settle_stream(stream, tokens, usage);
+        Ok(ChatCompletionStream::new(match reservation {
+            Some(reservation) => charge_stream(stream, reservation, usage),
+            None => stream,
+        }))
+    }
+
+    /// Applies the client's defaults to a chat request and checks it.
+    fn prepare_chat(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<ChatCompletionRequest, DavinciError> {
+        let mut request = request.clone();
+        request.merge_defaults(&self.inner.defaults);
+        if request.model.is_none() {
+            request.model = Some(self.inner.chat_model.clone());
+        }
+        request.validate()?;
+
+        let model = request.model.as_deref().unwrap_or_default();
+        // The answer of a chat request is only bounded by the context window,
+        // so without max_tokens it needs room for at least one token.
+        self.fit_context_window(model, &mut request.max_tokens, 1, |encoding| {
+            encoding.count_messages(&request.messages)
+        })?;
+        Ok(request)
+    }
+
+    /// Applies the client's defaults to a request and checks it.
+    fn prepare(&self, request: &CompletionRequest) -> Result<CompletionRequest, DavinciError> {
+        let mut request = request.clone();
+        request.merge_defaults(&self.inner.defaults);
+        if request.model.is_none() {
+            request.model = Some(self.inner.model.clone());
+        }
+        request.validate()?;
+
+        // Every text of a batch is completed on its own, so the longest one must fit.
+        let model = request.model.as_deref().unwrap_or_default();
+        self.fit_context_window(
+            model,
+            &mut request.max_tokens,
+            COMPLETION_MAX_TOKENS,
+            |encoding| {
+                let suffix = request
+                    .suffix
+                    .as_deref()
+                    .map_or(0, |suffix| encoding.count(suffix));
+                let prompt = match &request.prompt {
+                    Prompt::Text(text) => encoding.count(text),
+                    Prompt::Batch(texts) => texts
+                        .iter()
+                        .map(|text| encoding.count(text))
+                        .max()
+                        .unwrap_or_default(),
+                };
+                prompt + suffix
+            },
+        )?;
+        Ok(request)
+    }
+
+    /// Checks that the prompt plus `max_tokens` fits in the context window of `model`,
+    /// as the client's [`ContextWindowPolicy`] says. A request without `max_tokens`
+    /// is checked with `default_max_tokens`, the tokens the server generates then.
+    ///
+    /// The prompt is only tokenized when the registry knows the model.
+    fn fit_context_window(
+        &self,
+        model: &str,
+        max_tokens: &mut Option<u32>,
+        default_max_tokens: u32,
+        prompt_tokens: impl FnOnce(Encoding) -> usize,
+    ) -> Result<(), DavinciError> {
+        let policy = self.inner.context_window_policy;
+        if policy == ContextWindowPolicy::Ignore {
+            return Ok(());
+        }
+        let context_length = match self.inner.models.context_length(model) {
+            Some(context_length) => context_length,
+            None => return Ok(()),
+        };
+
+        let prompt_tokens = prompt_tokens(Encoding::for_model_or_default(model));
+        let prompt_tokens = u32::try_from(prompt_tokens).unwrap_or(u32::MAX);
+        let requested = max_tokens.unwrap_or(default_max_tokens);
+        if prompt_tokens.saturating_add(requested) <= context_length {
+            return Ok(());
+        }
+
+        if policy == ContextWindowPolicy::Clamp && prompt_tokens < context_length {
+            *max_tokens = Some(context_length - prompt_tokens);
+            return Ok(());
+        }
+        Err(DavinciError::ContextLengthExceeded {
+            model: model.to_string(),
+            context_length,
+            prompt_tokens,
+            max_tokens: requested,
+        })
+    }
+
+    /// Returns the tokens a completion request reserves in the rate limiter:
+    /// the tokens of every prompt, plus `max_tokens` for every choice of every prompt.
+    ///
+    /// The prompts are only tokenized when the client limits the tokens per minute.
+    fn completion_cost(&self, request: &CompletionRequest) -> u32 {
+        if !self.limits_tokens() {
+            return 0;
+        }
+        let (prompt_tokens, completion_tokens) = self.completion_tokens(request);
+        prompt_tokens.saturating_add(completion_tokens)
+    }
+
+    /// Returns the estimated prompt and completion tokens of a completion request:
+    /// the tokens of every prompt, and `max_tokens` for every choice of every prompt.
+    fn completion_tokens(&self, request: &CompletionRequest) -> (u32, u32) {
+        let encoding = Encoding::for_model_or_default(request.model.as_deref().unwrap_or_default());
+        let prompts = match &request.prompt {
+            Prompt::Text(text) => vec![text.as_str()],
+            Prompt::Batch(texts) => texts.iter().map(String::as_str).collect(),
+        };
+        let prompt_tokens: usize = prompts.iter().map(|text| encoding.count(text)).sum();
+        let choices = request.best_of.or(request.n).unwrap_or(1) as usize;
+        let completion_tokens = (request.max_tokens.unwrap_or(COMPLETION_MAX_TOKENS) as usize)
+            .saturating_mul(choices)
+            .saturating_mul(prompts.len());
+
+        (saturate(prompt_tokens), saturate(completion_tokens))
+    }
+
+    /// Returns the tokens a chat request reserves in the rate limiter:
+    /// the tokens of the messages, plus `max_tokens` for every choice.
+    fn chat_cost(&self, request: &ChatCompletionRequest) -> u32 {
+        if !self.limits_tokens() {
+            return 0;
+        }
+        let (prompt_tokens, completion_tokens) = self.chat_tokens(request);
+        prompt_tokens.saturating_add(completion_tokens)
+    }
+
+    /// Returns the estimated prompt and completion tokens of a chat request:
+    /// the tokens of the messages, and `max_tokens` for every choice.
+    fn chat_tokens(&self, request: &ChatCompletionRequest) -> (u32, u32) {
+        let encoding = Encoding::for_model_or_default(request.model.as_deref().unwrap_or_default());
+        let prompt_tokens = encoding.count_messages(&request.messages);
+        let choices = request.n.unwrap_or(1) as usize;
+        let completion_tokens =
+            (request.max_tokens.unwrap_or_default() as usize).saturating_mul(choices);
+
+        (saturate(prompt_tokens), saturate(completion_tokens))
+    }
+
+    /// Returns the prompt and completion tokens a chat request is expected to cost:
+    /// the tokens of the messages, and `max_tokens` for every choice.
+    ///
+    /// Without `max_tokens`, the answer is counted as [`CHAT_EXPECTED_MAX_TOKENS`],
+    /// or the rest of the context window of the model if the registry knows it and it is less.
+    /// The real cost is charged from the usage once the response arrives.
+    fn chat_budget_tokens(&self, request: &ChatCompletionRequest) -> (u32, u32) {
+        let (prompt_tokens, completion_tokens) = self.chat_tokens(request);
+        if request.max_tokens.is_some() {
+            return (prompt_tokens, completion_tokens);
+        }
+        let model = request.model.as_deref().unwrap_or_default();
+        let answer = self.inner.models.context_length(model).map_or(
+            CHAT_EXPECTED_MAX_TOKENS,
+            |context_length| {
+                context_length
+                    .saturating_sub(prompt_tokens)
+                    .min(CHAT_EXPECTED_MAX_TOKENS)
+            },
+        );
+        (prompt_tokens, answer.saturating_mul(request.n.unwrap_or(1)))
+    }
+
+    fn limits_tokens(&self) -> bool {
+        self.inner
+            .rate_limiter
+            .as_ref()
+            .is_some_and(RateLimiter::limits_tokens)
+    }
+
+    /// Replaces the estimated tokens of a request by the ones it used, once they are known.
+    fn settle(&self, estimated: u32, usage: Option<&Usage>) {
+        if let (Some(rate_limiter), Some(usage)) = (&self.inner.rate_limiter, usage) {
+            rate_limiter.settle(estimated, usage.total_tokens);
+        }
+    }
+
+    /// Replaces the estimated tokens of a stream by the usage of its first chunk that has one.
+    fn settle_stream<S, T>(
+        &self,
+        stream: S,
+        estimated: u32,
+        usage: fn(&T) -> Option<&Usage>,
+    ) -> BoxStream<'static, Result<T, DavinciError>>
+    where
+        S: Stream<Item = Result<T, DavinciError>> + Send + 'static,
+        T: Send + 'static,
+    {
+        match &self.inner.rate_limiter {
+            Some(rate_limiter) if estimated > 0 => {
+                rate_limiter.settle_stream(stream, estimated, usage)
+            }
+            _ => stream.boxed(),
+        }
+    }
+
+    /// Reserves what a request can cost in the budget of the client, if it has one,
+    /// or refuses it if the budget does not have that much left.
+    ///
+    /// `tokens` returns the estimated prompt and completion tokens, and is only called
+    /// when the client has a budget.
+    fn reserve_budget(
+        &self,
+        model: &str,
+        tokens: impl FnOnce() -> (u32, u32),
+    ) -> Result<Option<Reservation>, DavinciError> {
+        let budget = match &self.inner.budget {
+            Some(budget) => budget,
+            None => return Ok(None),
+        };
+        let (prompt_tokens, completion_tokens) = tokens();
+        match budget.limit() {
+            BudgetLimit::Dollars(_) => {
+                let price = *self.price(model)?;
+                let amount = price.cost(prompt_tokens, 0, completion_tokens);
+                budget.reserve(amount, Some(price)).map(Some)
+            }
+            BudgetLimit::Tokens(_) => {
+                let amount = f64::from(prompt_tokens) + f64::from(completion_tokens);
+                budget.reserve(amount, None).map(Some)
+            }
+        }
+    }
+
+    /// Sends `body` as JSON to `path` and parses the JSON response.
+    ///
+    /// `tokens` is the estimate reserved in the rate limiter, see [`DavinciClient::send`].
+    pub(crate) async fn post<B, R>(
+        &self,
+        path: &str,
+        body: &B,
+        tokens: u32,
+    ) -> Result<R, DavinciError>
+    where
+        B: Serialize + ?Sized,
+        R: DeserializeOwned,
+    {
+        self.cancellable(async {
+            let resp: Response = self.send(path, body, tokens).await?;
+            let body: String = resp.text().await?;
+
+            Ok(serde_json::from_str(&body)?)
+        })
+        .await
+    }
+
+    /// Runs `future`, unless the client's cancellation token is cancelled first.
+    async fn cancellable<F, T>(&self, future: F) -> Result<T, DavinciError>
+    where
+        F: Future<Output = Result<T, DavinciError>>,
+    {
+        match &self.options.cancellation {
+            Some(cancellation) => tokio::select! {
+                biased;
+                _ = cancellation.cancelled() => Err(DavinciError::Cancelled),
+                result = future => result,
+            },
+            None => future.await,
+        }
+    }
+
+    /// Sends `body` as JSON to `path` and returns the response if its status is a success.
+    ///
+    /// The failures the client's [`RetryPolicy`] allows are retried,
+    /// after the delay asked by the server or the policy's backoff.
+    /// Every attempt waits for its turn in the rate limiter, and the first one
+    /// reserves the estimated `tokens` of the request. They are given back if the request
+    /// fails, times out or is cancelled.
+    pub(crate) async fn send<B>(
+        &self,
+        path: &str,
+        body: &B,
+        tokens: u32,
+    ) -> Result<Response, DavinciError>
+    where
+        B: Serialize + ?Sized,
+    {
+        let retry = self.inner.retry;
+        let mut reservation = None;
+        let mut attempt = 1;
+        loop {
+            if let Some(rate_limiter) = &self.inner.rate_limiter {
+                let reserved = rate_limiter
+                    .reserve(if attempt == 1 { tokens } else { 0 })
+                    .await;
+                reservation.get_or_insert(reserved);
+            }
+            let (error, delay) = match self.send_once(path, body).await {
+                Ok(resp) => {
+                    if let Some(reservation) = reservation {
+                        reservation.keep();
+                    }
+                    return Ok(resp);
+                }
+                Err(failure) => failure,
+            };
+            if attempt >= retry.attempts() || !RetryPolicy::is_retryable(&error) {
+                return Err(error);
+            }
+
+            let delay = delay.unwrap_or_else(|| retry.delay(attempt));
+            if let Some(on_retry) = &self.inner.on_retry {
+                on_retry(&RetryAttempt {
+                    attempt,
+                    max_attempts: retry.attempts(),
+                    delay,
+                    error: &error,
+                });
+            }
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Sends the request once. A failure comes with the delay the server asks to wait, if any.
+    async fn send_once<B>(
+        &self,
+        path: &str,
+        body: &B,
+    ) -> Result<Response, (DavinciError, Option<Duration>)>
+    where
+        B: Serialize + ?Sized,
+    {
+        let http = self.options.http.as_ref().unwrap_or(&self.inner.http);
+        let mut request = http
+            .post(self.endpoint(path))
+            .bearer_auth(&self.inner.api_key)
+            .json(body);
+        if let Some(timeout) = self.timeout() {
+            request = request.timeout(timeout);
+        }
+        let request = request.build().map_err(|error| (error.into(), None))?;
+
+        let resp: Response = match &self.inner.cassette {
+            Some(cassette) => cassette.send(http, request).await,
+            None => http.execute(request).await.map_err(DavinciError::from),
+        }
+        .map_err(|error| (error, None))?;
+
+        let status = resp.status();
+        if !status.is_success() {
+            let delay = server_delay(resp.headers());
+            let body: String = resp.text().await.map_err(|error| (error.into(), delay))?;
+            return Err((DavinciError::from_response(status, body), delay));
+        }
+
+        Ok(resp)
+    }
+}
+
+/// A builder for [`DavinciClient`].
+///
+/// Every setting except the api key has a default value:
+///
+/// * `base_url` - the value of the `OPENAI_BASE_URL` environment variable if it is set,
+///   or [`DEFAULT_BASE_URL`].
+/// * `path_prefix` - [`DEFAULT_PATH_PREFIX`].
+/// * `model` - [`DEFAULT_MODEL`].
+/// * `chat_model` - [`DEFAULT_CHAT_MODEL`].
+/// * `prompt_template` - [`PromptTemplate::chat_transcript`].
+/// * the default parameters of the requests - none, so the server decides.
+/// * `models` - [`ModelRegistry::default`], the OpenAI models.
+/// * `context_window_policy` - [`ContextWindowPolicy::Reject`].
+/// * `truncation` - [`TruncationStrategy::DropOldest`].
+/// * `retry_policy` - [`RetryPolicy::default`], 3 attempts.
+/// * `rate_limiter` - none.
+/// * `cache` - none.
+/// * `cassette` - none, the requests are sent to the server.
+/// * `max_batch_prompts` - [`MAX_BATCH_PROMPTS`].
+/// * `pricing` - [`PricingTable::default`], the OpenAI prices.
+/// * `budget` - none.
+/// * `timeout` - [`DEFAULT_TIMEOUT`], 10 minutes.
+/// * `connect_timeout` - [`DEFAULT_CONNECT_TIMEOUT`], 10 seconds.
+pub struct DavinciClientBuilder {
+    http: Option<Client>,
+    api_key: Option<String>,
+    base_url: Option<String>,
+    path_prefix: String,
+    model: String,
+    chat_model: String,
+    defaults: CompletionRequest,
+    template: PromptTemplate,
+    models: ModelRegistry,
+    context_window_policy: ContextWindowPolicy,
+    truncation: TruncationStrategy,
+    retry: RetryPolicy,
+    on_retry: Option<RetryHook>,
+    rate_limiter: Option<RateLimiter>,
+    cache: Option<ResponseCache>,
+    cassette: Option<Cassette>,
+    max_batch_prompts: usize,
+    pricing: PricingTable,
+    budget: Option<Budget>,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+}
+
+impl Default for DavinciClientBuilder {
+    fn default() -> Self {
+        DavinciClientBuilder {
+            http: None,
+            api_key: None,
+            base_url: None,
+            path_prefix: String::from(DEFAULT_PATH_PREFIX),
+            model: String::from(DEFAULT_MODEL),
+            chat_model: String::from(DEFAULT_CHAT_MODEL),
+            defaults: CompletionRequest::default(),
+            template: PromptTemplate::default(),
+            models: ModelRegistry::default(),
+            context_window_policy: ContextWindowPolicy::default(),
+            truncation: TruncationStrategy::default(),
+            retry: RetryPolicy::default(),
+            on_retry: None,
+            rate_limiter: None,
+            cache: None,
+            cassette: None,
+            max_batch_prompts: MAX_BATCH_PROMPTS,
+            pricing: PricingTable::default(),
+            budget: None,
+            timeout: Some(DEFAULT_TIMEOUT),
+            connect_timeout: None,
+        }
+    }
+}
+
+impl fmt::Debug for DavinciClientBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DavinciClientBuilder")
+            .field("api_key", &self.api_key.as_ref().map(|_| "<redacted>"))
+            .field("base_url", &self.base_url)
+            .field("path_prefix", &self.path_prefix)
+            .field("model", &self.model)
+            .field("chat_model", &self.chat_model)
+            .field("defaults", &self.defaults)
+            .field("context_window_policy", &self.context_window_policy)
+            .field("truncation", &self.truncation)
+            .field("retry", &self.retry)
+            .field("rate_limiter", &self.rate_limiter)
+            .field("cache", &self.cache)
+            .field("cassette", &self.cassette)
+            .field("max_batch_prompts", &self.max_batch_prompts)
+            .field("budget", &self.budget)
+            .field("timeout", &self.timeout)
+            .field("connect_timeout", &self.connect_timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DavinciClientBuilder {
+    /// Sets the OpenAI api key. It is required.
+    pub fn api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// Sets the server the requests are sent to, such as `http://localhost:8080`
+    /// for a local OpenAI-compatible server.
+    ///
+    /// It takes precedence over the `OPENAI_BASE_URL` environment variable.
+    /// If the URL already ends with the path prefix, like `http://localhost:8080/v1`,
+    /// the prefix is not added twice.
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = Some(base_url.into());
+        self
+    }
+
+    /// Sets the prefix added between the base URL and the path of every endpoint.
+    ///
+    /// Use an empty prefix for servers that serve the API at the root of the base URL.
+    pub fn path_prefix(mut self, path_prefix: impl Into<String>) -> Self {
+        self.path_prefix = path_prefix.into();
+        self
+    }
+
+    /// Sets the model used by the completion requests.
+    pub fn model(mut self, model: impl Into<String>) -> Self {
+        self.model = model.into();
+        self
+    }
+
+    /// Sets the model used by the chat completion requests.
+    pub fn chat_model(mut self, chat_model: impl Into<String>) -> Self {
+        self.chat_model = chat_model.into();
+        self
+    }
+
+    /// Sets the template that renders the prompt of [`DavinciClient::ask`],
+    /// of [`DavinciClient::conversation`], and of [`DavinciClient::ask_conversation`]
+    /// for the conversations with the default template.
+    pub fn prompt_template(mut self, template: PromptTemplate) -> Self {
+        self.template = template;
+        self
+    }
+
+    /// Sets the parameters applied to the requests that do not set them.
+    /// Its prompt is ignored.
+    pub fn defaults(mut self, defaults: CompletionRequest) -> Self {
+        self.defaults = defaults;
+        self
+    }
+
+    /// Sets the default maximum number of tokens to generate.
+    pub fn max_tokens(mut self, max_tokens: u32) -> Self {
+        self.defaults.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Sets the default sampling temperature, between `0.0` and `2.0`.
+    pub fn temperature(mut self, temperature: f64) -> Self {
+        self.defaults.temperature = Some(temperature);
+        self
+    }
+
+    /// Sets the default nucleus sampling probability mass, between `0.0` and `1.0`.
+    pub fn top_p(mut self, top_p: f64) -> Self {
+        self.defaults.top_p = Some(top_p);
+        self
+    }
+
+    /// Sets the default frequency penalty, between `-2.0` and `2.0`.
+    pub fn frequency_penalty(mut self, frequency_penalty: f64) -> Self {
+        self.defaults.frequency_penalty = Some(frequency_penalty);
+        self
+    }
+
+    /// Sets the default presence penalty, between `-2.0` and `2.0`.
+    pub fn presence_penalty(mut self, presence_penalty: f64) -> Self {
+        self.defaults.presence_penalty = Some(presence_penalty);
+        self
+    }
+
+    /// Sets the default sequences where the model stops generating.
+    pub fn stop<I, S>(mut self, stop: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.defaults.stop = Some(Stop::Many(stop.into_iter().map(Into::into).collect()));
+        self
+    }
+
+    /// Sets the registry of the context lengths of the models.
+    pub fn models(mut self, models: ModelRegistry) -> Self {
+        self.models = models;
+        self
+    }
+
+    /// Sets the context length of a model the default registry does not know,
+    /// such as one of a self-hosted server.
+    pub fn context_length(mut self, model: impl Into<String>, context_length: u32) -> Self {
+        self.models.insert(model, context_length);
+        self
+    }
+
+    /// Sets what the client does with a request that does not fit
+    /// in the context window of its model.
+    pub fn context_window_policy(mut self, policy: ContextWindowPolicy) -> Self {
+        self.context_window_policy = policy;
+        self
+    }
+
+    /// Sets how the transcript of a conversation too long for the context window is shortened.
+    pub fn truncation(mut self, truncation: TruncationStrategy) -> Self {
+        self.truncation = truncation;
+        self
+    }
+
+    /// Sets how the failed requests are retried. Use [`RetryPolicy::none`] to never retry.
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sets a function called before every retry, with the failed attempt,
+    /// its error and the delay before the next one.
+    pub fn on_retry<F>(mut self, on_retry: F) -> Self
+    where
+        F: Fn(&RetryAttempt<'_>) + Send + Sync + 'static,
+    {
+        self.on_retry = Some(Arc::new(on_retry));
+        self
+    }
+
+    /// Sets a limiter of requests and tokens per minute.
+    /// Give clones of the same limiter to several clients to share the quota between them.
+    pub fn rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Sets a cache of the responses to completion and chat completion requests.
+    /// Give clones of the same cache to several clients to share it between them.
+    pub fn cache(mut self, cache: ResponseCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Sets a cassette that records the requests and their responses to a file,
+    /// or replays them without any network access.
+    pub fn cassette(mut self, cassette: Cassette) -> Self {
+        self.cassette = Some(cassette);
+        self
+    }
+
+    /// Sets the maximum number of prompts sent in one completion request by
+    /// [`DavinciClient::complete_batch`], for servers with a different limit.
+    pub fn max_batch_prompts(mut self, max_batch_prompts: usize) -> Self {
+        self.max_batch_prompts = max_batch_prompts;
+        self
+    }
+
+    /// Sets the prices of the models, such as a table loaded with [`PricingTable::from_file`].
+    pub fn pricing(mut self, pricing: PricingTable) -> Self {
+        self.pricing = pricing;
+        self
+    }
+
+    /// Sets a limit on what the client can spend, in dollars or tokens.
+    /// Give clones of the same budget to several clients to share it between them.
+    pub fn budget(mut self, budget: Budget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Sets the maximum time a whole request can take, from sending it to reading the response.
+    /// Every attempt of a retried request has this much time.
+    ///
+    /// A request that takes longer fails with [`DavinciError::Timeout`].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the maximum time to open a connection.
+    /// A connection that takes longer fails with [`DavinciError::Timeout`].
+    ///
+    /// It is ignored when a custom client is given with [`DavinciClientBuilder::http_client`],
+    /// as the connect timeout belongs to the connection pool.
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Uses a custom `reqwest::Client`, to configure proxies, certificates or headers.
+    pub fn http_client(mut self, http: Client) -> Self {
+        self.http = Some(http);
+        self
+    }
+
+    /// Builds the client.
+    ///
+    /// # Errors
+    ///
+    /// * [`DavinciError::InvalidInput`] if the api key is missing or empty, or the base URL is not valid.
+    /// * [`DavinciError::Transport`] if the connection pool could not be built.
+    pub fn build(self) -> Result<DavinciClient, DavinciError> {
+        let api_key = match self.api_key {
+            Some(api_key) if !api_key.trim().is_empty() => api_key,
+            _ => {
+                return Err(DavinciError::InvalidInput(String::from(
+                    "the api key can not be empty",
+                )))
+            }
+        };
+        let base_url = match self.base_url {
+            Some(base_url) => base_url,
+            None => std::env::var(BASE_URL_ENV)
+                .ok()
+                .filter(|base_url| !base_url.trim().is_empty())
+                .unwrap_or_else(|| String::from(DEFAULT_BASE_URL)),
+        };
+        let (base_url, path_prefix) = join_base_url(&base_url, &self.path_prefix)?;
+        if self.max_batch_prompts == 0 {
+            return Err(DavinciError::InvalidInput(String::from(
+                "max_batch_prompts must be positive",
+            )));
+        }
+
+        let custom_http = self.http.is_some();
+        let (http, connect_timeout) = match (self.http, self.connect_timeout) {
+            (Some(http), _) => (http, None),
+            (None, Some(connect_timeout)) => (
+                Client::builder().connect_timeout(connect_timeout).build()?,
+                Some(connect_timeout),
+            ),
+            (None, None) => (shared_http_client(), Some(DEFAULT_CONNECT_TIMEOUT)),
+        };
+
+        Ok(DavinciClient {
+            inner: Arc::new(ClientInner {
+                http,
+                custom_http,
+                api_key,
+                base_url,
+                path_prefix,
+                model: self.model,
+                chat_model: self.chat_model,
+                defaults: self.defaults,
+                template: self.template,
+                models: self.models,
+                context_window_policy: self.context_window_policy,
+                truncation: self.truncation,
+                retry: self.retry,
+                on_retry: self.on_retry,
+                rate_limiter: self.rate_limiter,
+                cache: self.cache,
+                cassette: self.cassette,
+                max_batch_prompts: self.max_batch_prompts,
+                pricing: self.pricing,
+                budget: self.budget,
+                timeout: self.timeout,
+                connect_timeout,
+            }),
+            options: RequestOptions::default(),
+        })
+    }
+}
+
+/// Converts a number of tokens to `u32`, saturating at `u32::MAX`.
+fn saturate(tokens: usize) -> u32 {
+    u32::try_from(tokens).unwrap_or(u32::MAX)
+}
+
+/// Checks the `tokens` argument of the question functions.
+fn check_tokens(tokens: i32) -> Result<u32, DavinciError> {
+    if tokens <= 0 {
+        return Err(DavinciError::InvalidInput(format!(
+            "tokens must be positive, got {}",
+            tokens
+        )));
+    }
+    Ok(tokens as u32)
+}
+
+/// Normalizes the base URL and the path prefix so that appending an endpoint path gives a valid URL.
+///
+/// The base URL loses its trailing slashes and the prefix gets a leading slash.
+/// When the base URL already ends with the prefix, the prefix is dropped.
+fn join_base_url(base_url: &str, path_prefix: &str) -> Result<(String, String), DavinciError> {
+    let base_url = base_url.trim().trim_end_matches('/');
+    if let Err(error) = reqwest::Url::parse(base_url) {
+        return Err(DavinciError::InvalidInput(format!(
+            "the base url {:?} is not valid: {}",
+            base_url, error
+        )));
+    }
+
+    let path_prefix = path_prefix.trim().trim_matches('/');
+    let path_prefix = if path_prefix.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", path_prefix)
+    };
+
+    if !path_prefix.is_empty() && base_url.ends_with(&path_prefix) {
+        Ok((base_url.to_string(), String::new()))
+    } else {
+        Ok((base_url.to_string(), path_prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn cassette(name: &str) -> Cassette {
+        Cassette::replay(format!(
+            "{}/tests/cassettes/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn create_completion_batch_splits_the_prompts_and_groups_the_choices() {
+        let client = DavinciClient::builder()
+            .api_key("sk-test")
+            .cassette(cassette("completion_batch.json"))
+            .max_batch_prompts(2)
+            .build()
+            .unwrap();
+        let request = CompletionRequest::builder()
+            .prompt(vec!["a", "b", "c"])
+            .n(2)
+            .max_tokens(5)
+            .build()
+            .unwrap();
+
+        let batch = client.create_completion_batch(&request).await.unwrap();
+
+        let texts: Vec<Vec<(u32, &str)>> = batch
+            .iter()
+            .map(|choices| {
+                choices
+                    .iter()
+                    .map(|choice| (choice.index, choice.text.as_str()))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            texts,
+            vec![
+                vec![(0, "a0"), (1, "a1")],
+                vec![(0, "b0"), (1, "b1")],
+                vec![(0, "c0"), (1, "c1")],
+            ]
+        );
+        // Both recorded requests were used, so the prompts were sent as two batches.
+        let error = client.create_completion_batch(&request).await.unwrap_err();
+        assert!(matches!(error, DavinciError::Cassette(_)));
+    }
+
+    #[test]
+    fn completion_tokens_saturate_instead_of_overflowing() {
+        let client = DavinciClient::builder()
+            .api_key("sk-test")
+            .model("gpt-3.5-turbo-instruct")
+            .context_window_policy(ContextWindowPolicy::Ignore)
+            .build()
+            .unwrap();
+        let request = CompletionRequest::builder()
+            .prompt(vec!["a", "b"])
+            .max_tokens(u32::MAX)
+            .best_of(u32::MAX)
+            .build()
+            .unwrap();
+
+        assert_eq!(client.completion_tokens(&request).1, u32::MAX);
+        assert!(client.estimate_cost(&request).unwrap() > 8_000.0);
+    }
+
+    /// Starts a server that reads the requests and sends `reply`, if any, then keeps
+    /// the connections open without sending anything else, and returns its URL.
+    async fn silent_server(reply: Option<&'static str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((mut connection, _)) = listener.accept().await {
+                let mut request = [0; 4096];
+                let _ = connection.read(&mut request).await;
+                if let Some(reply) = reply {
+                    let _ = connection.write_all(reply.as_bytes()).await;
+                }
+                connections.push(connection);
+            }
+        });
+        url
+    }
+
+    /// Returns a client of `url` that does not retry, with a limiter of 1,000 tokens
+    /// per minute and a budget of 1,000 tokens.
+    fn limited_client(url: &str) -> DavinciClient {
+        DavinciClient::builder()
+            .api_key("sk-test")
+            .base_url(url)
+            .retry_policy(RetryPolicy::none())
+            .rate_limiter(
+                RateLimiter::builder()
+                    .tokens_per_minute(1_000)
+                    .build()
+                    .unwrap(),
+            )
+            .budget(Budget::builder().tokens(1_000).build().unwrap())
+            .build()
+            .unwrap()
+    }
+
+    fn request() -> CompletionRequest {
+        CompletionRequest::builder()
+            .prompt("Write an epic")
+            .max_tokens(500)
+            .build()
+            .unwrap()
+    }
+
+    /// Asserts that nothing is left reserved or spent in the limiter and the budget of `client`.
+    async fn assert_released(client: &DavinciClient) {
+        let budget = client.budget().unwrap();
+        assert_eq!(budget.remaining(), 1_000.0);
+        assert_eq!(budget.spent(), 0.0);
+        // The whole minute of tokens is there, so taking it does not wait.
+        let acquire = client.rate_limiter().unwrap().acquire(1_000);
+        assert!(tokio::time::timeout(Duration::from_millis(100), acquire)
+            .await
+            .is_ok());
+    }
+
+    /// Cancels `cancellation` once the request has had the time to reach the server.
+    fn cancel_soon(cancellation: &CancellationToken) {
+        let cancellation = cancellation.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            cancellation.cancel();
+        });
+    }
+
+    #[tokio::test]
+    async fn cancelled_request_releases_its_reservations() {
+        let client = limited_client(&silent_server(None).await);
+        let cancellation = CancellationToken::new();
+        cancel_soon(&cancellation);
+
+        let result = client
+            .with_cancellation(cancellation)
+            .complete(&request())
+            .await;
+
+        assert!(matches!(result, Err(DavinciError::Cancelled)));
+        assert_released(&client).await;
+    }
+
+    #[tokio::test]
+    async fn timed_out_request_releases_its_reservations() {
+        let client = limited_client(&silent_server(None).await);
+
+        let result = client
+            .with_timeout(Duration::from_millis(50))
+            .complete(&request())
+            .await;
+
+        assert!(matches!(result, Err(DavinciError::Timeout(_))));
+        assert_released(&client).await;
+    }
+
+    #[tokio::test]
+    async fn cancelled_stream_releases_its_reservations() {
+        let client = limited_client(&silent_server(None).await);
+        let cancellation = CancellationToken::new();
+        cancel_soon(&cancellation);
+
+        let result = client
+            .with_cancellation(cancellation)
+            .stream_completion(&request())
+            .await;
+
+        assert!(matches!(result, Err(DavinciError::Cancelled)));
+        assert_released(&client).await;
+    }
+
+    #[tokio::test]
+    async fn timed_out_stream_releases_its_reservations() {
+        let client = limited_client(&silent_server(None).await);
+
+        let result = client
+            .with_timeout(Duration::from_millis(50))
+            .stream_completion(&request())
+            .await;
+
+        assert!(matches!(result, Err(DavinciError::Timeout(_))));
+        assert_released(&client).await;
+    }
+
+    #[tokio::test]
+    async fn stream_cancelled_after_it_started_ends_with_cancelled() {
+        let reply = concat!(
+            "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\nconnection: close\r\n\r\n",
+            "data: {\"id\":\"cmpl-1\",\"object\":\"text_completion\",\"created\":0,",
+            "\"model\":\"gpt-3.5-turbo-instruct\",\"choices\":[{\"text\":\"Sing\",\"index\":0,",
+            "\"logprobs\":null,\"finish_reason\":null}]}\n\n",
+        );
+        let client = limited_client(&silent_server(Some(reply)).await);
+        let cancellation = CancellationToken::new();
+
+        let mut stream = client
+            .with_cancellation(cancellation.clone())
+            .stream_completion(&request())
+            .await
+            .unwrap();
+        let first = stream.next().await.unwrap().unwrap();
+        assert_eq!(first.text(), Some("Sing"));
+        cancellation.cancel();
+
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(DavinciError::Cancelled))
+        ));
+        assert!(stream.next().await.is_none());
+        drop(stream);
+        // The server started to generate, so the budget keeps the estimate of the stream.
+        let budget = client.budget().unwrap();
+        assert_eq!(budget.remaining(), 1_000.0 - budget.spent());
+        assert!(budget.spent() > 500.0);
+    }
+}
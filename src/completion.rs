@@ -0,0 +1,704 @@
+//! Types of the `/v1/completions` endpoint.
+//!
+//! [`CompletionRequest`] exposes every parameter of the endpoint.
+//! The parameters that are not set are left out of the JSON body,
+//! so the server (or the defaults of the [`crate::DavinciClient`]) decides their value.
+//!
+//! ```
+//! use davinci::CompletionRequest;
+//!
+//! let request = CompletionRequest::builder()
+//!     .prompt("Say this is a test")
+//!     .max_tokens(16)
+//!     .temperature(0.0)
+//!     .top_p(0.95)
+//!     .stop(["\n", "Human:"])
+//!     .build()
+//!     .unwrap();
+//!
+//! assert_eq!(
+//!     serde_json::to_string(&request).unwrap(),
+//!     r#"{"prompt":"Say this is a test","max_tokens":16,"temperature":0.0,"top_p":0.95,"stop":["\n","Human:"]}"#
+//! );
+//! ```
+use crate::{DavinciError, ModelPrice, PricingTable, StreamOptions};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The maximum number of stop sequences accepted by the API.
+pub const MAX_STOP_SEQUENCES: usize = 4;
+
+/// The maximum value of `logprobs` accepted by the API.
+pub const MAX_LOGPROBS: u8 = 5;
+
+/// The maximum number of prompts sent in one request by [`crate::DavinciClient::complete_batch`].
+pub const MAX_BATCH_PROMPTS: usize = 20;
+
+/// The prompt of a completion: a single text or several texts completed in one request.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Prompt {
+    /// A single text.
+    Text(String),
+    /// Several texts, each one gets its own choices.
+    Batch(Vec<String>),
+}
+
+impl Prompt {
+    /// Returns the number of texts in the prompt.
+    pub fn len(&self) -> usize {
+        match self {
+            Prompt::Text(_) => 1,
+            Prompt::Batch(prompts) => prompts.len(),
+        }
+    }
+
+    /// Returns `true` if the prompt does not contain any text.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for Prompt {
+    fn default() -> Self {
+        Prompt::Text(String::new())
+    }
+}
+
+impl From<String> for Prompt {
+    fn from(prompt: String) -> Self {
+        Prompt::Text(prompt)
+    }
+}
+
+impl From<&str> for Prompt {
+    fn from(prompt: &str) -> Self {
+        Prompt::Text(prompt.to_string())
+    }
+}
+
+impl From<Vec<String>> for Prompt {
+    fn from(prompts: Vec<String>) -> Self {
+        Prompt::Batch(prompts)
+    }
+}
+
+impl From<Vec<&str>> for Prompt {
+    fn from(prompts: Vec<&str>) -> Self {
+        Prompt::Batch(prompts.into_iter().map(String::from).collect())
+    }
+}
+
+/// The sequences where the model stops generating.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Stop {
+    /// A single sequence.
+    One(String),
+    /// Up to [`MAX_STOP_SEQUENCES`] sequences.
+    Many(Vec<String>),
+}
+
+impl Stop {
+    /// Returns the number of sequences.
+    pub fn len(&self) -> usize {
+        match self {
+            Stop::One(_) => 1,
+            Stop::Many(sequences) => sequences.len(),
+        }
+    }
+
+    /// Returns `true` if there is no sequence.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl From<&str> for Stop {
+    fn from(sequence: &str) -> Self {
+        Stop::One(sequence.to_string())
+    }
+}
+
+impl From<String> for Stop {
+    fn from(sequence: String) -> Self {
+        Stop::One(sequence)
+    }
+}
+
+impl From<Vec<String>> for Stop {
+    fn from(sequences: Vec<String>) -> Self {
+        Stop::Many(sequences)
+    }
+}
+
+/// The body of a request to the `/v1/completions` endpoint.
+///
+/// Build one with [`CompletionRequest::builder`], which checks the ranges of the parameters.
+/// The `None` fields are not serialized.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CompletionRequest {
+    /// The model that completes the prompt.
+    /// When it is `None`, the client's default model is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// The text to complete.
+    pub prompt: Prompt,
+    /// The text that comes after the completion.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suffix: Option<String>,
+    /// The maximum number of tokens to generate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    /// The sampling temperature, between `0.0` and `2.0`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    /// The nucleus sampling probability mass, between `0.0` and `1.0`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    /// How many completions to generate for each prompt.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
+    /// How many completions to generate on the server before returning the `n` best ones.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub best_of: Option<u32>,
+    /// Whether to stream the completion back as it is generated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    /// The options of the stream, for a streamed request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
+    /// How many of the most likely tokens to return the log probabilities of, up to [`MAX_LOGPROBS`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<u8>,
+    /// Whether to return the prompt in front of the completion.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub echo: Option<bool>,
+    /// The sequences where the model stops generating.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Stop>,
+    /// The penalty for tokens that already appeared in the text, between `-2.0` and `2.0`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
+    /// The penalty for tokens based on how often they appeared in the text, between `-2.0` and `2.0`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+    /// A bias between `-100` and `100` added to the likelihood of the given token ids.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<BTreeMap<u32, i32>>,
+    /// A seed to make sampling as deterministic as the server allows.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    /// An identifier of the end user, to help the provider detect abuse.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+impl CompletionRequest {
+    /// Returns a builder to configure a new request.
+    pub fn builder() -> CompletionRequestBuilder {
+        CompletionRequestBuilder::default()
+    }
+
+    /// Returns a request for `prompt` with every other parameter unset.
+    pub fn new(prompt: impl Into<Prompt>) -> CompletionRequest {
+        CompletionRequest {
+            prompt: prompt.into(),
+            ..CompletionRequest::default()
+        }
+    }
+
+    /// Fills the parameters that are not set with the ones of `defaults`.
+    pub(crate) fn merge_defaults(&mut self, defaults: &CompletionRequest) {
+        fill(&mut self.model, &defaults.model);
+        fill(&mut self.suffix, &defaults.suffix);
+        fill(&mut self.max_tokens, &defaults.max_tokens);
+        fill(&mut self.temperature, &defaults.temperature);
+        fill(&mut self.top_p, &defaults.top_p);
+        fill(&mut self.n, &defaults.n);
+        fill(&mut self.best_of, &defaults.best_of);
+        fill(&mut self.stream, &defaults.stream);
+        fill(&mut self.stream_options, &defaults.stream_options);
+        fill(&mut self.logprobs, &defaults.logprobs);
+        fill(&mut self.echo, &defaults.echo);
+        fill(&mut self.stop, &defaults.stop);
+        fill(&mut self.presence_penalty, &defaults.presence_penalty);
+        fill(&mut self.frequency_penalty, &defaults.frequency_penalty);
+        fill(&mut self.logit_bias, &defaults.logit_bias);
+        fill(&mut self.seed, &defaults.seed);
+        fill(&mut self.user, &defaults.user);
+    }
+
+    /// Returns `true` if the request asks for the most likely answer, with a temperature of `0`,
+    /// and is not streamed, so that sending it again is expected to give the same response.
+    pub fn is_deterministic(&self) -> bool {
+        self.temperature == Some(0.0) && self.stream != Some(true)
+    }
+
+    /// Checks that every parameter is in the range accepted by the API.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DavinciError::InvalidInput`] describing the first parameter out of range.
+    pub fn validate(&self) -> Result<(), DavinciError> {
+        if self.prompt.is_empty() {
+            return Err(DavinciError::InvalidInput(String::from(
+                "the prompt must contain at least one text",
+            )));
+        }
+        if self.max_tokens == Some(0) {
+            return Err(DavinciError::InvalidInput(String::from(
+                "max_tokens must be positive",
+            )));
+        }
+        check_range("temperature", self.temperature, 0.0, 2.0)?;
+        check_range("top_p", self.top_p, 0.0, 1.0)?;
+        check_range("presence_penalty", self.presence_penalty, -2.0, 2.0)?;
+        check_range("frequency_penalty", self.frequency_penalty, -2.0, 2.0)?;
+        if self.n == Some(0) {
+            return Err(DavinciError::InvalidInput(String::from(
+                "n must be positive",
+            )));
+        }
+        if let Some(best_of) = self.best_of {
+            if best_of < self.n.unwrap_or(1) {
+                return Err(DavinciError::InvalidInput(format!(
+                    "best_of must be greater than or equal to n, got {}",
+                    best_of
+                )));
+            }
+        }
+        if let Some(logprobs) = self.logprobs {
+            if logprobs > MAX_LOGPROBS {
+                return Err(DavinciError::InvalidInput(format!(
+                    "logprobs must be at most {}, got {}",
+                    MAX_LOGPROBS, logprobs
+                )));
+            }
+        }
+        if let Some(stop) = &self.stop {
+            if stop.len() > MAX_STOP_SEQUENCES {
+                return Err(DavinciError::InvalidInput(format!(
+                    "at most {} stop sequences are allowed, got {}",
+                    MAX_STOP_SEQUENCES,
+                    stop.len()
+                )));
+            }
+        }
+        if let Some(logit_bias) = &self.logit_bias {
+            if let Some((token, bias)) = logit_bias
+                .iter()
+                .find(|(_, bias)| !(-100..=100).contains(*bias))
+            {
+                return Err(DavinciError::InvalidInput(format!(
+                    "the logit bias of token {} must be between -100 and 100, got {}",
+                    token, bias
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sets `field` to `default` if it is not set.
+pub(crate) fn fill<T: Clone>(field: &mut Option<T>, default: &Option<T>) {
+    if field.is_none() {
+        *field = default.clone();
+    }
+}
+
+/// Checks that an optional parameter is between `min` and `max`.
+pub(crate) fn check_range(
+    name: &str,
+    value: Option<f64>,
+    min: f64,
+    max: f64,
+) -> Result<(), DavinciError> {
+    match value {
+        Some(value) if !(min..=max).contains(&value) => Err(DavinciError::InvalidInput(format!(
+            "{} must be between {} and {}, got {}",
+            name, min, max, value
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// A builder for [`CompletionRequest`].
+#[derive(Debug, Clone, Default)]
+pub struct CompletionRequestBuilder {
+    request: CompletionRequest,
+}
+
+impl CompletionRequestBuilder {
+    /// Sets the model that completes the prompt.
+    pub fn model(mut self, model: impl Into<String>) -> Self {
+        self.request.model = Some(model.into());
+        self
+    }
+
+    /// Sets the text, or the texts, to complete.
+    pub fn prompt(mut self, prompt: impl Into<Prompt>) -> Self {
+        self.request.prompt = prompt.into();
+        self
+    }
+
+    /// Sets the text that comes after the completion.
+    pub fn suffix(mut self, suffix: impl Into<String>) -> Self {
+        self.request.suffix = Some(suffix.into());
+        self
+    }
+
+    /// Sets the maximum number of tokens to generate.
+    pub fn max_tokens(mut self, max_tokens: u32) -> Self {
+        self.request.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Sets the sampling temperature, between `0.0` and `2.0`.
+    pub fn temperature(mut self, temperature: f64) -> Self {
+        self.request.temperature = Some(temperature);
+        self
+    }
+
+    /// Sets the nucleus sampling probability mass, between `0.0` and `1.0`.
+    pub fn top_p(mut self, top_p: f64) -> Self {
+        self.request.top_p = Some(top_p);
+        self
+    }
+
+    /// Sets how many completions to generate for each prompt.
+    pub fn n(mut self, n: u32) -> Self {
+        self.request.n = Some(n);
+        self
+    }
+
+    /// Sets how many completions to generate on the server before returning the `n` best ones.
+    pub fn best_of(mut self, best_of: u32) -> Self {
+        self.request.best_of = Some(best_of);
+        self
+    }
+
+    /// Sets whether to stream the completion back as it is generated.
+    pub fn stream(mut self, stream: bool) -> Self {
+        self.request.stream = Some(stream);
+        self
+    }
+
+    /// Sets how many of the most likely tokens to return the log probabilities of.
+    pub fn logprobs(mut self, logprobs: u8) -> Self {
+        self.request.logprobs = Some(logprobs);
+        self
+    }
+
+    /// Sets whether to return the prompt in front of the completion.
+    pub fn echo(mut self, echo: bool) -> Self {
+        self.request.echo = Some(echo);
+        self
+    }
+
+    /// Sets the sequences where the model stops generating.
+    pub fn stop<I, S>(mut self, stop: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.request.stop = Some(Stop::Many(stop.into_iter().map(Into::into).collect()));
+        self
+    }
+
+    /// Sets the penalty for tokens that already appeared in the text, between `-2.0` and `2.0`.
+    pub fn presence_penalty(mut self, presence_penalty: f64) -> Self {
+        self.request.presence_penalty = Some(presence_penalty);
+        self
+    }
+
+    /// Sets the penalty for tokens based on how often they appeared, between `-2.0` and `2.0`.
+    pub fn frequency_penalty(mut self, frequency_penalty: f64) -> Self {
+        self.request.frequency_penalty = Some(frequency_penalty);
+        self
+    }
+
+    /// Sets the bias added to the likelihood of a token id, between `-100` and `100`.
+    /// It can be called once for every token.
+    pub fn logit_bias(mut self, token: u32, bias: i32) -> Self {
+        self.request
+            .logit_bias
+            .get_or_insert_with(BTreeMap::new)
+            .insert(token, bias);
+        self
+    }
+
+    /// Sets the seed used for sampling.
+    pub fn seed(mut self, seed: i64) -> Self {
+        self.request.seed = Some(seed);
+        self
+    }
+
+    /// Sets the identifier of the end user.
+    pub fn user(mut self, user: impl Into<String>) -> Self {
+        self.request.user = Some(user.into());
+        self
+    }
+
+    /// Builds the request.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DavinciError::InvalidInput`] if a parameter is out of the range accepted by the API.
+    pub fn build(self) -> Result<CompletionRequest, DavinciError> {
+        self.request.validate()?;
+        Ok(self.request)
+    }
+}
+
+/// Why the model stopped generating a choice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The model reached a natural stop point or a stop sequence.
+    Stop,
+    /// The completion reached `max_tokens` or the end of the context window.
+    Length,
+    /// The completion was cut by the content filter.
+    ContentFilter,
+    /// The model called a tool.
+    ToolCalls,
+    /// The model called a function, in the deprecated function calling API.
+    FunctionCall,
+    /// A reason this crate does not know.
+    #[serde(other)]
+    Unknown,
+}
+
+/// The token usage of a request, used for billing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Usage {
+    /// The number of tokens in the prompt.
+    pub prompt_tokens: u32,
+    /// The number of generated tokens.
+    #[serde(default)]
+    pub completion_tokens: u32,
+    /// The number of tokens in the prompt and the completion.
+    pub total_tokens: u32,
+    /// The details of the prompt tokens, sent by the servers that cache prompts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens_details: Option<PromptTokensDetails>,
+}
+
+impl Usage {
+    /// Returns the number of prompt tokens read from the prompt cache, which cost less.
+    pub fn cached_tokens(&self) -> u32 {
+        self.prompt_tokens_details
+            .map_or(0, |details| details.cached_tokens)
+    }
+
+    /// Returns the cost of the tokens in dollars, at `price`.
+    ///
+    /// ```
+    /// use davinci::{ModelPrice, Usage};
+    ///
+    /// let usage: Usage = serde_json::from_str(r#"{
+    ///     "prompt_tokens": 2000, "completion_tokens": 500, "total_tokens": 2500,
+    ///     "prompt_tokens_details": {"cached_tokens": 1000}
+    /// }"#).unwrap();
+    /// let price = ModelPrice::new(2.5, 10.0).cached_prompt(1.25);
+    ///
+    /// // 1000 * 2.5 + 1000 * 1.25 + 500 * 10 dollars per million tokens.
+    /// assert!((usage.cost(&price) - 0.00875).abs() < 1e-12);
+    /// ```
+    pub fn cost(&self, price: &ModelPrice) -> f64 {
+        price.cost(
+            self.prompt_tokens.saturating_sub(self.cached_tokens()),
+            self.cached_tokens(),
+            self.completion_tokens,
+        )
+    }
+}
+
+/// The details of the prompt tokens of a [`Usage`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PromptTokensDetails {
+    /// The number of prompt tokens read from the prompt cache.
+    #[serde(default)]
+    pub cached_tokens: u32,
+}
+
+/// The log probabilities of the tokens of a choice, returned when the request sets `logprobs`.
+///
+/// The natural logarithm is used, so a log probability of `0.0` means the model was certain
+/// of the token, and the probability of a token is `logprob.exp()`.
+///
+/// ```
+/// use davinci::Logprobs;
+///
+/// let logprobs: Logprobs = serde_json::from_str(r#"{
+///     "tokens": [" Paris", "."],
+///     "token_logprobs": [-0.1, -0.5],
+///     "top_logprobs": [{" Paris": -0.1, " Lyon": -2.5}, {".": -0.5}],
+///     "text_offset": [0, 6]
+/// }"#).unwrap();
+///
+/// assert!((logprobs.sum() - -0.6).abs() < 1e-9);
+/// assert!((logprobs.min_confidence().unwrap() - (-0.5f64).exp()).abs() < 1e-9);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Logprobs {
+    /// The generated tokens.
+    #[serde(default)]
+    pub tokens: Vec<String>,
+    /// The log probability of every token.
+    /// It is `None` for the first token of an echoed prompt, which has nothing before it.
+    #[serde(default)]
+    pub token_logprobs: Vec<Option<f64>>,
+    /// The log probabilities of the most likely tokens at every position.
+    #[serde(default)]
+    pub top_logprobs: Vec<Option<BTreeMap<String, f64>>>,
+    /// The position in the text where every token starts.
+    #[serde(default)]
+    pub text_offset: Vec<u32>,
+}
+
+impl Logprobs {
+    /// Returns the log probability of the whole sequence: the sum of the log probabilities
+    /// of its tokens. The tokens without one are skipped.
+    pub fn sum(&self) -> f64 {
+        self.token_logprobs.iter().flatten().sum()
+    }
+
+    /// Returns the probability of the whole sequence, `sum().exp()`.
+    pub fn sequence_probability(&self) -> f64 {
+        self.sum().exp()
+    }
+
+    /// Returns the mean log probability of the tokens, which does not shrink with the length
+    /// of the text like [`Logprobs::sum`] does. Returns `None` if there is no token.
+    pub fn mean(&self) -> Option<f64> {
+        let count = self.token_logprobs.iter().flatten().count();
+        if count == 0 {
+            None
+        } else {
+            Some(self.sum() / count as f64)
+        }
+    }
+
+    /// Returns the probability, between `0.0` and `1.0`, of every token.
+    pub fn confidences(&self) -> Vec<(&str, Option<f64>)> {
+        self.tokens
+            .iter()
+            .zip(self.token_logprobs.iter())
+            .map(|(token, logprob)| (token.as_str(), logprob.map(f64::exp)))
+            .collect()
+    }
+
+    /// Returns the probability of the least likely token, the weakest point of the answer.
+    /// Returns `None` if there is no token.
+    pub fn min_confidence(&self) -> Option<f64> {
+        self.token_logprobs
+            .iter()
+            .flatten()
+            .copied()
+            .reduce(f64::min)
+            .map(f64::exp)
+    }
+
+    /// Appends the tokens of a later part of the same choice, as sent by a stream.
+    pub fn extend(&mut self, other: Logprobs) {
+        self.tokens.extend(other.tokens);
+        self.token_logprobs.extend(other.token_logprobs);
+        self.top_logprobs.extend(other.top_logprobs);
+        self.text_offset.extend(other.text_offset);
+    }
+}
+
+/// One of the completions generated for a prompt.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Choice {
+    /// The generated text.
+    pub text: String,
+    /// The position of the choice in the response.
+    pub index: u32,
+    /// The log probabilities of the tokens, if they were requested with `logprobs`.
+    #[serde(default)]
+    pub logprobs: Option<Logprobs>,
+    /// Why the model stopped generating. It is `None` while a choice is being streamed.
+    #[serde(default)]
+    pub finish_reason: Option<FinishReason>,
+}
+
+/// The response of the `/v1/completions` endpoint.
+///
+/// ```
+/// use davinci::{CompletionResponse, FinishReason};
+///
+/// let response: CompletionResponse = serde_json::from_str(r#"{
+///     "id": "cmpl-1", "object": "text_completion", "created": 1589478378, "model": "text-davinci-003",
+///     "choices": [{"text": " This is a test", "index": 0, "logprobs": null, "finish_reason": "length"}],
+///     "usage": {"prompt_tokens": 5, "completion_tokens": 4, "total_tokens": 9}
+/// }"#).unwrap();
+///
+/// assert_eq!(response.text(), Some(" This is a test"));
+/// assert_eq!(response.finish_reason(), Some(FinishReason::Length));
+/// assert_eq!(response.usage.unwrap().total_tokens, 9);
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompletionResponse {
+    /// The unique identifier of the completion.
+    pub id: String,
+    /// The type of object, always `text_completion`.
+    pub object: String,
+    /// The Unix timestamp, in seconds, of when the completion was created.
+    pub created: u64,
+    /// The model that generated the completion.
+    pub model: String,
+    /// The generated completions.
+    pub choices: Vec<Choice>,
+    /// The token usage of the request. Some compatible servers do not send it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    /// The backend configuration the model ran with, if the server sends it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_fingerprint: Option<String>,
+}
+
+/// The name the response had before it was made public.
+pub type OpenAIResponse = CompletionResponse;
+
+impl CompletionResponse {
+    /// Returns the first choice, which is the only one unless `n` or a batch prompt was used.
+    pub fn first_choice(&self) -> Option<&Choice> {
+        self.choices.first()
+    }
+
+    /// Returns the text of the first choice.
+    pub fn text(&self) -> Option<&str> {
+        self.first_choice().map(|choice| choice.text.as_str())
+    }
+
+    /// Returns why the model stopped generating the first choice.
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.first_choice().and_then(|choice| choice.finish_reason)
+    }
+
+    /// Returns `true` if the first choice was cut because it reached `max_tokens`.
+    pub fn is_truncated(&self) -> bool {
+        self.finish_reason() == Some(FinishReason::Length)
+    }
+
+    /// Returns the cost of the request in dollars, from its usage and the price of its model,
+    /// or `None` if the server did not send the usage or `pricing` does not know the model.
+    pub fn cost(&self, pricing: &PricingTable) -> Option<f64> {
+        Some(self.usage?.cost(pricing.price(&self.model)?))
+    }
+
+    /// Consumes the response and returns the text of the first choice.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DavinciError::EmptyChoices`] if the response does not contain any choice.
+    pub fn into_text(self) -> Result<String, DavinciError> {
+        match self.choices.into_iter().next() {
+            Some(choice) => Ok(choice.text),
+            None => Err(DavinciError::EmptyChoices),
+        }
+    }
+}
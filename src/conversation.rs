@@ -0,0 +1,243 @@
+//! Conversations that keep their transcript across questions.
+//!
+//! A [`Conversation`] stores the context and every question and answer so far.
+//! [`crate::DavinciClient::ask_conversation`] and [`crate::DavinciClient::chat_conversation`]
+//! send the whole transcript with the new question and append the answer to it,
+//! so the model remembers the previous turns.
+//!
+//! ```no_run
+//! use davinci::{Conversation, DavinciClient};
+//!
+//! # async fn run(client: DavinciClient) -> Result<(), davinci::DavinciError> {
+//! let mut conversation = Conversation::new("The assistant is helpful, creative, clever, and very friendly");
+//!
+//! client.ask_conversation(&mut conversation, "Hello, who are you?", 100).await?;
+//! client.ask_conversation(&mut conversation, "What did I just ask you?", 100).await?;
+//!
+//! assert_eq!(conversation.turns().len(), 2);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! When the transcript gets too long for the context window of the model, the client sends
+//! only the turns its [`TruncationStrategy`] keeps. The conversation itself keeps every turn.
+use crate::{ChatMessage, PromptTemplate};
+use serde::{Deserialize, Serialize};
+
+/// A question of the human and the answer of the AI.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Turn {
+    /// The question or phrase of the human.
+    pub question: String,
+    /// The answer of the model.
+    pub answer: String,
+}
+
+/// How a transcript too long for the context window of the model is shortened.
+///
+/// Every strategy keeps the context and the new question, and drops whole turns
+/// until the prompt plus `max_tokens`, the budget reserved for the answer, fits in the window.
+/// If the request does not fit even without any turn, it is sent with none,
+/// and the client's [`crate::ContextWindowPolicy`] decides.
+///
+/// ```
+/// use davinci::{Conversation, TruncationStrategy};
+///
+/// let mut conversation = Conversation::new("You are a poet");
+/// for question in ["One", "Two", "Three", "Four", "Five"] {
+///     conversation.push_turn(question, "...");
+/// }
+/// let questions = |conversation: &Conversation| -> Vec<String> {
+///     conversation.turns().iter().map(|turn| turn.question.clone()).collect()
+/// };
+///
+/// // A prompt of at most 70 bytes has room for two turns.
+/// let fits = |conversation: &Conversation| conversation.prompt("Six").len() <= 70;
+///
+/// let kept = conversation.truncated(TruncationStrategy::DropOldest, fits);
+/// assert_eq!(questions(&kept), ["Four", "Five"]);
+/// let kept = conversation.truncated(TruncationStrategy::KeepLast(1), |_| true);
+/// assert_eq!(questions(&kept), ["Five"]);
+/// let kept = conversation.truncated(TruncationStrategy::MiddleOut, fits);
+/// assert_eq!(questions(&kept), ["One", "Five"]);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TruncationStrategy {
+    /// Sends the whole transcript, even if it does not fit.
+    Disabled,
+    /// Drops the oldest turns first.
+    #[default]
+    DropOldest,
+    /// Keeps at most the last `n` turns, even when more would fit,
+    /// and drops the oldest of them first if they still do not fit.
+    KeepLast(usize),
+    /// Drops the turns in the middle of the transcript first, keeping the first turns,
+    /// which often set up the conversation, and the most recent ones.
+    MiddleOut,
+}
+
+impl TruncationStrategy {
+    /// Returns the indices of `len` turns in the order they are dropped,
+    /// and how many of them are dropped even if the transcript fits.
+    fn drop_order(&self, len: usize) -> (Vec<usize>, usize) {
+        match self {
+            TruncationStrategy::Disabled => (Vec::new(), 0),
+            TruncationStrategy::DropOldest => ((0..len).collect(), 0),
+            TruncationStrategy::KeepLast(n) => ((0..len).collect(), len.saturating_sub(*n)),
+            TruncationStrategy::MiddleOut => {
+                // From the middle outwards, the older of two turns as far from the middle first.
+                let mut order: Vec<usize> = (0..len).collect();
+                order.sort_by_key(|index| ((2 * index).abs_diff(len - 1), *index));
+                (order, 0)
+            }
+        }
+    }
+}
+
+/// The context and the transcript of a conversation with the model.
+///
+/// The transcript is rendered into a prompt by the conversation's [`PromptTemplate`]
+/// for completions, or as system, user and assistant messages for chat completions.
+///
+/// ```
+/// use davinci::Conversation;
+///
+/// let mut conversation = Conversation::new("You are a poet");
+/// conversation.push_turn("Hello", "Hello, dear friend");
+///
+/// assert_eq!(
+///     conversation.prompt("Write a haiku?"),
+///     "You are a poet\nHuman: Hello\nAI: Hello, dear friend\nHuman: Write a haiku?\nAI:"
+/// );
+/// assert_eq!(conversation.messages("Write a haiku").len(), 4);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Conversation {
+    context: String,
+    turns: Vec<Turn>,
+    /// The template is not serialized: a deserialized conversation uses the default one.
+    #[serde(skip)]
+    template: PromptTemplate,
+}
+
+impl Conversation {
+    /// Returns a conversation without any turn.
+    ///
+    /// # Parameters
+    ///
+    /// * `context` - The context of the conversation.
+    ///   It tells the model how it should behave during the whole conversation.
+    pub fn new(context: impl Into<String>) -> Conversation {
+        Conversation::with_template(context, PromptTemplate::default())
+    }
+
+    /// Returns a conversation without any turn, rendered with `template`.
+    pub fn with_template(context: impl Into<String>, template: PromptTemplate) -> Conversation {
+        Conversation {
+            context: context.into(),
+            turns: Vec::new(),
+            template,
+        }
+    }
+
+    /// Returns the template the prompt is rendered with.
+    pub fn template(&self) -> &PromptTemplate {
+        &self.template
+    }
+
+    /// Changes the template the prompt is rendered with.
+    pub fn set_template(&mut self, template: PromptTemplate) {
+        self.template = template;
+    }
+
+    /// Returns the context of the conversation.
+    pub fn context(&self) -> &str {
+        &self.context
+    }
+
+    /// Returns the questions and answers so far, from the oldest to the newest.
+    pub fn turns(&self) -> &[Turn] {
+        &self.turns
+    }
+
+    /// Returns `true` if nothing has been asked yet.
+    pub fn is_empty(&self) -> bool {
+        self.turns.is_empty()
+    }
+
+    /// Appends a question and its answer to the transcript.
+    ///
+    /// The answer is trimmed, as the model usually starts it with a space or a new line.
+    pub fn push_turn(&mut self, question: impl Into<String>, answer: impl AsRef<str>) {
+        self.turns.push(Turn {
+            question: question.into(),
+            answer: answer.as_ref().trim().to_string(),
+        });
+    }
+
+    /// Removes every turn and keeps the context.
+    pub fn clear(&mut self) {
+        self.turns.clear();
+    }
+
+    /// Returns a copy of the conversation with only the turns `strategy` keeps,
+    /// as few dropped as possible for `fits` to accept it.
+    ///
+    /// `fits` must not accept a conversation with more turns after refusing one with fewer,
+    /// as the number of turns to drop is found by a binary search.
+    pub fn truncated<F>(&self, strategy: TruncationStrategy, mut fits: F) -> Conversation
+    where
+        F: FnMut(&Conversation) -> bool,
+    {
+        let (order, forced) = strategy.drop_order(self.turns.len());
+        let dropping = |count: usize| {
+            let mut kept = vec![true; self.turns.len()];
+            for index in &order[..count] {
+                kept[*index] = false;
+            }
+            Conversation {
+                context: self.context.clone(),
+                turns: self
+                    .turns
+                    .iter()
+                    .zip(kept)
+                    .filter(|(_, kept)| *kept)
+                    .map(|(turn, _)| turn.clone())
+                    .collect(),
+                template: self.template.clone(),
+            }
+        };
+
+        // The smallest count that fits, between the forced count and every turn.
+        let (mut low, mut high) = (forced, order.len());
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if fits(&dropping(middle)) {
+                high = middle;
+            } else {
+                low = middle + 1;
+            }
+        }
+        dropping(low)
+    }
+
+    /// Returns the completion prompt that asks `question` after the whole transcript.
+    pub fn prompt(&self, question: &str) -> String {
+        self.template.render(&self.context, &self.turns, question)
+    }
+
+    /// Returns the chat messages that ask `question` after the whole transcript:
+    /// the context as a system message, then the turns as user and assistant messages.
+    pub fn messages(&self, question: &str) -> Vec<ChatMessage> {
+        let mut messages = Vec::with_capacity(self.turns.len() * 2 + 2);
+        if !self.context.is_empty() {
+            messages.push(ChatMessage::system(self.context.as_str()));
+        }
+        for turn in &self.turns {
+            messages.push(ChatMessage::user(turn.question.as_str()));
+            messages.push(ChatMessage::assistant(turn.answer.as_str()));
+        }
+        messages.push(ChatMessage::user(question));
+        messages
+    }
+}
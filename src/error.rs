@@ -0,0 +1,337 @@
+//! Errors returned by the crate.
+//!
+//! Every fallible function in this crate returns a [`DavinciError`],
+//! so a failed request never panics the caller.
+use crate::BudgetLimit;
+use reqwest::StatusCode;
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// An error object returned by the OpenAI API, together with the HTTP status of the response.
+///
+/// The API answers failed requests with a body like
+/// `{"error": {"message": "...", "type": "...", "param": null, "code": "..."}}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiError {
+    /// The HTTP status code of the response.
+    pub status: StatusCode,
+    /// The human readable description of the error.
+    pub message: String,
+    /// The kind of error, such as `invalid_request_error` or `insufficient_quota`.
+    pub error_type: Option<String>,
+    /// The request parameter the error is about, if any.
+    pub param: Option<String>,
+    /// The machine readable code, such as `invalid_api_key` or `context_length_exceeded`.
+    pub code: Option<String>,
+}
+
+impl ApiError {
+    /// Parses an OpenAI error body.
+    ///
+    /// Returns `None` if the body is not an OpenAI error object.
+    ///
+    /// ```
+    /// use davinci::ApiError;
+    /// use reqwest::StatusCode;
+    ///
+    /// let body = r#"{"error": {"message": "Rate limit reached", "type": "requests", "param": null, "code": "rate_limit_exceeded"}}"#;
+    /// let error = ApiError::from_body(StatusCode::TOO_MANY_REQUESTS, body).unwrap();
+    /// assert!(error.is_rate_limit());
+    /// assert!(!error.is_auth());
+    /// assert_eq!(error.code.as_deref(), Some("rate_limit_exceeded"));
+    /// ```
+    pub fn from_body(status: StatusCode, body: &str) -> Option<ApiError> {
+        let response: ErrorResponse = serde_json::from_str(body).ok()?;
+        Some(ApiError {
+            status,
+            message: response.error.message,
+            error_type: response.error.error_type,
+            param: response.error.param,
+            code: response.error.code,
+        })
+    }
+
+    /// Returns `true` if the request was rejected because of a rate limit (status 429).
+    ///
+    /// A 429 caused by an exhausted quota is not a rate limit, as retrying will not help;
+    /// see [`ApiError::is_quota_exceeded`].
+    pub fn is_rate_limit(&self) -> bool {
+        self.status == StatusCode::TOO_MANY_REQUESTS && !self.is_quota_exceeded()
+    }
+
+    /// Returns `true` if the account has run out of credits.
+    pub fn is_quota_exceeded(&self) -> bool {
+        self.error_type.as_deref() == Some("insufficient_quota")
+            || self.code.as_deref() == Some("insufficient_quota")
+    }
+
+    /// Returns `true` if the api key is missing, wrong or not allowed to use the resource.
+    pub fn is_auth(&self) -> bool {
+        self.status == StatusCode::UNAUTHORIZED
+            || self.status == StatusCode::FORBIDDEN
+            || self.code.as_deref() == Some("invalid_api_key")
+    }
+
+    /// Returns `true` if the prompt plus `max_tokens` is longer than the model's context window.
+    pub fn is_context_length_exceeded(&self) -> bool {
+        self.code.as_deref() == Some("context_length_exceeded")
+    }
+
+    /// Returns `true` if the requested model does not exist or can not be used.
+    pub fn is_model_not_found(&self) -> bool {
+        self.code.as_deref() == Some("model_not_found")
+    }
+
+    /// Returns `true` if the error happened on the server side (status 5xx).
+    pub fn is_server_error(&self) -> bool {
+        self.status.is_server_error()
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.status)?;
+        if let Some(code) = self.code.as_ref().or(self.error_type.as_ref()) {
+            write!(f, " ({})", code)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[derive(Debug, Deserialize)]
+struct ErrorDetails {
+    message: String,
+    #[serde(rename = "type", default)]
+    error_type: Option<String>,
+    #[serde(default)]
+    param: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_code")]
+    code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: ErrorDetails,
+}
+
+/// OpenAI sends the code as a string, but some compatible servers send a number.
+fn deserialize_code<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(
+        match Option::<serde_json::Value>::deserialize(deserializer)? {
+            Some(serde_json::Value::String(code)) => Some(code),
+            Some(serde_json::Value::Null) | None => None,
+            Some(other) => Some(other.to_string()),
+        },
+    )
+}
+
+/// The error type for every request made to the OpenAI API.
+#[derive(Debug)]
+pub enum DavinciError {
+    /// The request could not be sent or the response could not be read
+    /// (DNS failure, connection reset, TLS error...).
+    Transport(reqwest::Error),
+    /// The connection could not be opened within the connect timeout of the client,
+    /// or the request did not complete within its timeout.
+    Timeout(reqwest::Error),
+    /// The request was cancelled with its [`crate::CancellationToken`].
+    Cancelled,
+    /// The server answered with a non-success status code and a body
+    /// that is not an OpenAI error object.
+    Status {
+        /// The HTTP status code of the response.
+        status: StatusCode,
+        /// The raw response body.
+        body: String,
+    },
+    /// The server answered with an OpenAI error object.
+    Api(ApiError),
+    /// The response body could not be parsed.
+    Deserialize(serde_json::Error),
+    /// The response did not contain any choice.
+    EmptyChoices,
+    /// The arguments of the request are not valid, so it was not sent.
+    InvalidInput(String),
+    /// A file, such as the one of a [`crate::Budget`] or a [`crate::PricingTable`],
+    /// could not be read or written.
+    Io {
+        /// The path of the file.
+        path: PathBuf,
+        /// The error of the operating system.
+        error: std::io::Error,
+    },
+    /// The [`crate::Cassette`] of the client could not be read or written,
+    /// or it has no recorded response for the request.
+    Cassette(String),
+    /// The request could cost more than what is left of the [`crate::Budget`] of the client,
+    /// so it was not sent.
+    BudgetExceeded {
+        /// The limit of the budget.
+        limit: BudgetLimit,
+        /// What was already spent or reserved by the requests in flight, in dollars or tokens.
+        spent: f64,
+        /// What the request could cost, in dollars or tokens.
+        requested: f64,
+    },
+    /// The prompt plus `max_tokens` does not fit in the context window of the model,
+    /// so the request was not sent. See [`crate::ContextWindowPolicy`].
+    ContextLengthExceeded {
+        /// The model of the request.
+        model: String,
+        /// The context length of the model, in tokens.
+        context_length: u32,
+        /// The number of tokens of the prompt.
+        prompt_tokens: u32,
+        /// The `max_tokens` of the request or, if it does not set one, the tokens the server
+        /// generates then: `16` for a completion, and `1` for a chat completion,
+        /// as the answer needs at least one token.
+        max_tokens: u32,
+    },
+}
+
+impl fmt::Display for DavinciError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DavinciError::Transport(error) => {
+                write!(f, "error while sending the request: {}", error)
+            }
+            DavinciError::Timeout(error) => write!(f, "the request timed out: {}", error),
+            DavinciError::Cancelled => write!(f, "the request was cancelled"),
+            DavinciError::Status { status, body } => {
+                write!(f, "the server answered with status {}: {}", status, body)
+            }
+            DavinciError::Api(error) => {
+                write!(f, "the OpenAI API returned an error: {}", error)
+            }
+            DavinciError::Deserialize(error) => {
+                write!(f, "error while parsing the response: {}", error)
+            }
+            DavinciError::EmptyChoices => write!(f, "the response does not contain any choice"),
+            DavinciError::InvalidInput(message) => write!(f, "invalid input: {}", message),
+            DavinciError::Io { path, error } => {
+                write!(f, "error while accessing {}: {}", path.display(), error)
+            }
+            DavinciError::Cassette(message) => write!(f, "cassette error: {}", message),
+            DavinciError::BudgetExceeded {
+                limit,
+                spent,
+                requested,
+            } => write!(
+                f,
+                "the request could cost {}, but {} of the budget of {} are already spent",
+                limit.format(*requested),
+                limit.format(*spent),
+                limit.format(limit.amount())
+            ),
+            DavinciError::ContextLengthExceeded {
+                model,
+                context_length,
+                prompt_tokens,
+                max_tokens,
+            } => write!(
+                f,
+                "the prompt has {} tokens and max_tokens is {}, {} tokens over the context window \
+                 of {} tokens of {}",
+                prompt_tokens,
+                max_tokens,
+                self.excess_tokens().unwrap_or_default(),
+                context_length,
+                model
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DavinciError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DavinciError::Transport(error) | DavinciError::Timeout(error) => Some(error),
+            DavinciError::Api(error) => Some(error),
+            DavinciError::Deserialize(error) => Some(error),
+            DavinciError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl DavinciError {
+    /// Returns the OpenAI error object, if the server returned one.
+    pub fn api_error(&self) -> Option<&ApiError> {
+        match self {
+            DavinciError::Api(error) => Some(error),
+            _ => None,
+        }
+    }
+
+    /// Returns the HTTP status code of the response, if the server answered.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            DavinciError::Status { status, .. } => Some(*status),
+            DavinciError::Api(error) => Some(error.status),
+            DavinciError::Transport(error) => error.status(),
+            _ => None,
+        }
+    }
+
+    /// Returns by how many tokens a request goes over the context window of its model,
+    /// if the error is [`DavinciError::ContextLengthExceeded`].
+    pub fn excess_tokens(&self) -> Option<u32> {
+        match self {
+            DavinciError::ContextLengthExceeded {
+                context_length,
+                prompt_tokens,
+                max_tokens,
+                ..
+            } => Some(
+                prompt_tokens
+                    .saturating_add(*max_tokens)
+                    .saturating_sub(*context_length),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Builds the error for a file that could not be read or written.
+    pub(crate) fn io(path: &Path, error: std::io::Error) -> DavinciError {
+        DavinciError::Io {
+            path: path.to_path_buf(),
+            error,
+        }
+    }
+
+    /// Builds the error for a non-success response.
+    pub(crate) fn from_response(status: StatusCode, body: String) -> DavinciError {
+        match ApiError::from_body(status, &body) {
+            Some(error) => DavinciError::Api(error),
+            None => DavinciError::Status { status, body },
+        }
+    }
+}
+
+impl From<ApiError> for DavinciError {
+    fn from(error: ApiError) -> Self {
+        DavinciError::Api(error)
+    }
+}
+
+impl From<reqwest::Error> for DavinciError {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            DavinciError::Timeout(error)
+        } else {
+            DavinciError::Transport(error)
+        }
+    }
+}
+
+impl From<serde_json::Error> for DavinciError {
+    fn from(error: serde_json::Error) -> Self {
+        DavinciError::Deserialize(error)
+    }
+}
@@ -0,0 +1,291 @@
+//! Many requests sent concurrently, with a bound on how many are in flight.
+//!
+//! [`crate::DavinciClient::run_many`] takes the requests lazily from an iterator, so it can go
+//! through tens of thousands of them without holding them all in memory, and keeps at most
+//! [`RunOptions::concurrency`] in flight. Every request goes through the client, so the retries,
+//! rate limiter, cache and timeouts apply to each one, and the rate limiter is shared by all.
+//!
+//! ```no_run
+//! use davinci::{CompletionRequest, DavinciClient, ResultOrder, RunOptions};
+//! use futures::StreamExt;
+//!
+//! # async fn run() -> Result<(), davinci::DavinciError> {
+//! let client = DavinciClient::new("vj-JZkjskhdksKXOlncknjckukNKKnkJNKJNkNKNk")?;
+//! let questions = (0..10_000).map(|i| CompletionRequest::new(format!("What is {} squared?", i)));
+//!
+//! let options = RunOptions::new(16)
+//!     .order(ResultOrder::Completion)
+//!     .on_progress(|progress| eprintln!("{}/{:?} done, {} failed", progress.completed, progress.total, progress.failed));
+//!
+//! let mut results = client.run_many(questions, options);
+//! while let Some((index, result)) = results.next().await {
+//!     match result {
+//!         Ok(response) => println!("{}: {:?}", index, response.text()),
+//!         Err(error) => eprintln!("{}: {}", index, error),
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use crate::sync::lock;
+use crate::DavinciError;
+use futures::stream::{self, BoxStream};
+use futures::{Future, StreamExt};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// The order in which [`crate::DavinciClient::run_many`] returns the results.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResultOrder {
+    /// In the order of the requests. A slow request holds back the results after it,
+    /// and the requests that come after them, as at most `concurrency` results are kept waiting.
+    #[default]
+    Input,
+    /// As soon as every request completes.
+    Completion,
+}
+
+/// How far [`crate::DavinciClient::run_many`] has got, given to the progress callback.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// The number of requests that completed, successfully or not.
+    pub completed: usize,
+    /// The number of requests that failed.
+    pub failed: usize,
+    /// The number of requests, if the iterator knows it.
+    pub total: Option<usize>,
+}
+
+/// A function called every time a request of [`crate::DavinciClient::run_many`] completes.
+type ProgressHook = Arc<dyn Fn(&Progress) + Send + Sync>;
+
+/// The settings of [`crate::DavinciClient::run_many`].
+///
+/// The default options keep 8 requests in flight and return the results in the order
+/// of the requests.
+#[derive(Clone)]
+pub struct RunOptions {
+    concurrency: usize,
+    order: ResultOrder,
+    on_progress: Option<ProgressHook>,
+}
+
+impl fmt::Debug for RunOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RunOptions")
+            .field("concurrency", &self.concurrency)
+            .field("order", &self.order)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        RunOptions::new(8)
+    }
+}
+
+impl RunOptions {
+    /// Returns options that keep at most `concurrency` requests in flight.
+    /// `0` is treated as `1`.
+    pub fn new(concurrency: usize) -> RunOptions {
+        RunOptions {
+            concurrency: concurrency.max(1),
+            order: ResultOrder::default(),
+            on_progress: None,
+        }
+    }
+
+    /// Sets the order of the results. Default: [`ResultOrder::Input`].
+    pub fn order(mut self, order: ResultOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Sets a function called every time a request completes, with the progress so far.
+    /// It is called from the task that polls the results, so it should return quickly.
+    pub fn on_progress<F>(mut self, on_progress: F) -> Self
+    where
+        F: Fn(&Progress) + Send + Sync + 'static,
+    {
+        self.on_progress = Some(Arc::new(on_progress));
+        self
+    }
+
+    /// Returns the maximum number of requests in flight.
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+}
+
+/// Runs `run` on every input, with at most `options.concurrency` in flight, and returns
+/// the results with the position of their input.
+pub(crate) fn fan_out<I, T, F, Fut>(
+    inputs: I,
+    options: RunOptions,
+    run: F,
+) -> BoxStream<'static, (usize, Result<T, DavinciError>)>
+where
+    I: IntoIterator,
+    I::IntoIter: Send + 'static,
+    F: Fn(I::Item) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<T, DavinciError>> + Send + 'static,
+    T: Send + 'static,
+{
+    let inputs = inputs.into_iter();
+    let total = match inputs.size_hint() {
+        (lower, Some(upper)) if lower == upper => Some(upper),
+        _ => None,
+    };
+    let progress = Arc::new(Mutex::new(Progress {
+        total,
+        ..Progress::default()
+    }));
+    let on_progress = options.on_progress;
+
+    let futures = stream::iter(inputs.enumerate()).map(move |(index, input)| {
+        let request = run(input);
+        let progress = progress.clone();
+        let on_progress = on_progress.clone();
+        async move {
+            let result = request.await;
+            let snapshot = {
+                let mut progress = lock(&progress);
+                progress.completed += 1;
+                if result.is_err() {
+                    progress.failed += 1;
+                }
+                *progress
+            };
+            // Called without the lock, so that a slow callback does not hold back the others.
+            if let Some(on_progress) = &on_progress {
+                on_progress(&snapshot);
+            }
+            (index, result)
+        }
+    });
+
+    match options.order {
+        ResultOrder::Input => futures.buffered(options.concurrency).boxed(),
+        ResultOrder::Completion => futures.buffer_unordered(options.concurrency).boxed(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CompletionBackend, CompletionRequest, MockBackend, MockRequest};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Runs the inputs against `backend`, the later inputs taking less time to complete.
+    fn run(
+        backend: &MockBackend,
+        inputs: usize,
+        options: RunOptions,
+    ) -> BoxStream<'static, (usize, Result<String, DavinciError>)> {
+        let backend = backend.clone();
+        fan_out(0..inputs, options, move |input| {
+            let backend = backend.clone();
+            async move {
+                let delay = (inputs - input) as u64 * 10;
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                backend
+                    .complete(&CompletionRequest::new(input.to_string()))
+                    .await
+            }
+        })
+    }
+
+    fn prompts(backend: &MockBackend) -> Vec<String> {
+        backend
+            .requests()
+            .into_iter()
+            .map(|request| match request {
+                MockRequest::Completion(request) => match request.prompt {
+                    crate::Prompt::Text(prompt) => prompt,
+                    prompt => panic!("unexpected prompt {:?}", prompt),
+                },
+                request => panic!("unexpected request {:?}", request),
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn results_come_in_the_order_of_the_inputs() {
+        let backend = MockBackend::new();
+        for _ in 0..4 {
+            backend.push_completion("answer");
+        }
+
+        let results: Vec<_> = run(&backend, 4, RunOptions::new(4)).collect().await;
+
+        let indices: Vec<usize> = results.iter().map(|(index, _)| *index).collect();
+        assert_eq!(indices, vec![0, 1, 2, 3]);
+        // The later inputs completed first, as they all were in flight together.
+        assert_eq!(prompts(&backend), vec!["3", "2", "1", "0"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn results_come_as_they_complete() {
+        let backend = MockBackend::new();
+        for _ in 0..4 {
+            backend.push_completion("answer");
+        }
+
+        let options = RunOptions::new(4).order(ResultOrder::Completion);
+        let results: Vec<_> = run(&backend, 4, options).collect().await;
+
+        let indices: Vec<usize> = results.iter().map(|(index, _)| *index).collect();
+        assert_eq!(indices, vec![3, 2, 1, 0]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn at_most_concurrency_inputs_are_in_flight() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let (counter, maximum) = (in_flight.clone(), most.clone());
+
+        let results: Vec<_> = fan_out(0..10, RunOptions::new(3), move |_| {
+            let (counter, maximum) = (counter.clone(), maximum.clone());
+            async move {
+                let now = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                maximum.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                counter.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            }
+        })
+        .collect()
+        .await;
+
+        assert_eq!(results.len(), 10);
+        assert_eq!(most.load(Ordering::SeqCst), 3);
+        assert_eq!(in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn progress_counts_the_completed_and_failed_inputs() {
+        let backend = MockBackend::new();
+        backend
+            .push_completion("answer")
+            .push_error(DavinciError::EmptyChoices)
+            .push_completion("answer");
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let progress = seen.clone();
+        let options = RunOptions::new(1).on_progress(move |update| lock(&progress).push(*update));
+
+        let results: Vec<_> = run(&backend, 3, options).collect().await;
+
+        assert!(results[1].1.is_err());
+        let seen = lock(&seen);
+        let counts: Vec<(usize, usize, Option<usize>)> = seen
+            .iter()
+            .map(|progress| (progress.completed, progress.failed, progress.total))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![(1, 0, Some(3)), (2, 1, Some(3)), (3, 1, Some(3))]
+        );
+    }
+}
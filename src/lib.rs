@@ -2,9 +2,10 @@
 //!
 //! This library provides a function for asking questions to the OpenAI Davinci model and getting a response.
 //! # davinci
-//! `davinci` is the main function, and it has 4 parameters:
+//! `davinci` is the main function. It is async, so it can be awaited from any tokio application,
+//! and it has 4 parameters:
 //! * `api_key` -> String - This is the OpenAi api key.
-//!     It can be obtained [here](https://beta.openai.com/account/api-keys)
+//!   It can be obtained [here](https://beta.openai.com/account/api-keys)
 //! * `context` -> String - The context for the question.
 //! * `question` -> String - The question or phrase to ask the model.
 //! * `tokens` -> i32 - The maximum number of tokens to use in the response.
@@ -27,18 +28,22 @@
 //! Token generally corresponds to ~4 characters of text for common English text.
 //! This translates to roughly ¾ of a word (so 100 tokens ~= 75 words).
 //!
-//! Another thing to keep in mind, is that the `tokens` highest value is 2048 (4096 in new models).
+//! Another thing to keep in mind, is that the prompt plus `tokens` must fit in the context window of the model,
+//! 4097 tokens for `text-davinci-003`. A question that does not fit is rejected before it is sent,
+//! with an error that says how many tokens it goes over (see [`ModelRegistry`]).
 //!
-//! One way to know the number of tokens your prompt has is using [this site](https://beta.openai.com/tokenizer)
+//! One way to know the number of tokens your prompt has is using [this site](https://beta.openai.com/tokenizer),
+//! or offline with [`tokenizer::count_tokens`].
 //!
 //! ## Example of usage
 //! In this quick example we use davinci to find a answer to user's question.
 //!
-//! ```
+//! ```no_run
 //! use davinci::davinci;
 //! use std::io;
 //!
-//! fn main() {
+//! #[tokio::main]
+//! async fn main() {
 //!     let api: String = String::from("vj-JZkjskhdksKXOlncknjckukNKKnkJNKJNkNKNk");
 //!     let max_tokens: i32 = 100;
 //!     let context: String =
@@ -49,7 +54,7 @@
 //!     io::stdin()
 //!         .read_line(&mut question)
 //!         .expect("Error, you have to write something!");
-//!     let response: String = match davinci(api, context, question, max_tokens) {
+//!     let response: String = match davinci(api, context, question, max_tokens).await {
 //!         Ok(res) => res,
 //!         Err(error) => error.to_string(),
 //!     };
@@ -57,94 +62,111 @@
 //! }
 //! ```
 //!
-use reqwest::{Client, Error, Response};
-use serde::{Deserialize, Serialize};
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Parameters {
-    model: String,
-    prompt: String,
-    temperature: f64,
-    max_tokens: i32,
-    top_p: u8,
-    frequency_penalty: f64,
-    presence_penalty: f64,
-    stop: Vec<String>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Choice {
-    text: String,
-    index: u8,
-    logprobs: Option<i32>,
-    finish_reason: String,
-}
+//! ## Blocking usage
+//! Programs that do not use async can call the same function from the [`blocking`] module,
+//! which runs it on a runtime owned by the crate:
+//!
+//! ```no_run
+//! use davinci::blocking::davinci;
+//!
+//! let response = davinci(
+//!     String::from("vj-JZkjskhdksKXOlncknjckukNKKnkJNKJNkNKNk"),
+//!     String::from("The assistant is helpful, creative, clever, and very friendly"),
+//!     String::from("Hello, who are you?"),
+//!     100,
+//! );
+//! ```
+//!
+mod backend;
+pub mod blocking;
+mod budget;
+mod cache;
+mod cassette;
+mod chat;
+mod client;
+mod completion;
+mod conversation;
+mod error;
+mod fan_out;
+mod models;
+mod persist;
+mod pricing;
+mod rate_limit;
+mod retry;
+mod stream;
+mod sync;
+mod template;
+pub mod tokenizer;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Usage {
-    prompt_tokens: i32,
-    completion_tokens: i32,
-    total_tokens: i32,
-}
+pub use backend::{CompletionBackend, MockBackend, MockReply, MockRequest};
+pub use budget::{Budget, BudgetBuilder, BudgetLimit};
+pub use cache::{cache_key, CacheBackend, CacheEntry, DirectoryCache, MemoryCache, ResponseCache};
+pub use cassette::{
+    Cassette, CassetteMode, Interaction, RecordedRequest, RecordedResponse, REDACTED,
+};
+pub use chat::{
+    ChatChoice, ChatChunkChoice, ChatCompletionChunk, ChatCompletionRequest,
+    ChatCompletionRequestBuilder, ChatCompletionResponse, ChatCompletionStream, ChatDelta,
+    ChatMessage, FunctionCall, FunctionCallDelta, FunctionDefinition, Role, Tool, ToolCall,
+    ToolCallDelta, ToolChoice,
+};
+pub use client::{
+    DavinciClient, DavinciClientBuilder, BASE_URL_ENV, DEFAULT_BASE_URL, DEFAULT_CHAT_MODEL,
+    DEFAULT_CONNECT_TIMEOUT, DEFAULT_MODEL, DEFAULT_PATH_PREFIX, DEFAULT_TIMEOUT,
+};
+pub use completion::{
+    Choice, CompletionRequest, CompletionRequestBuilder, CompletionResponse, FinishReason,
+    Logprobs, OpenAIResponse, Prompt, PromptTokensDetails, Stop, Usage, MAX_BATCH_PROMPTS,
+    MAX_LOGPROBS, MAX_STOP_SEQUENCES,
+};
+pub use conversation::{Conversation, TruncationStrategy, Turn};
+pub use error::{ApiError, DavinciError};
+pub use fan_out::{Progress, ResultOrder, RunOptions};
+pub use models::{ContextWindowPolicy, ModelRegistry};
+pub use pricing::{ModelPrice, PricingTable};
+pub use rate_limit::{RateLimiter, RateLimiterBuilder};
+pub use retry::{RetryAttempt, RetryPolicy};
+pub use stream::{CompletionChunk, CompletionStream, StreamOptions};
+pub use template::{PromptTemplate, PromptTemplateBuilder, CONTEXT_VARIABLE};
+/// The token given to [`DavinciClient::with_cancellation`] to cancel requests.
+pub use tokio_util::sync::CancellationToken;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct OpenAIResponse {
-    id: String,
-    object: String,
-    created: u64,
-    model: String,
-    choices: Vec<Choice>,
-    usage: Usage,
-}
-#[tokio::main]
+/// Asks a question to the davinci model.
+///
+/// It is a shortcut for [`DavinciClient::ask`] with the default configuration.
+/// Applications that make many requests should build a [`DavinciClient`] once and reuse it.
+///
 /// # Parameters
 ///
 /// * `api_key` - The OpenAI API key.
-///     This must be well written, as it will throw an error if not.
+///   This must be well written, as it will throw an error if not.
 /// * `context` - The context for the question.
-///     The context is important for good responses as it tells the model how it should be it's behavior.
+///   The context is important for good responses as it tells the model how it should be it's behavior.
 /// * `question` - The question or phrase to ask the model.
 /// * `tokens` - The maximum number of tokens to use in the response.
 ///
 /// # Returns
 ///
-/// Returns the model's response as a Ok(String) or an Error.
+/// Returns the model's response as a Ok(String) or a [`DavinciError`].
+///
+/// # Errors
+///
+/// * [`DavinciError::InvalidInput`] if `api_key` is empty or `tokens` is not positive.
+/// * [`DavinciError::ContextLengthExceeded`] if the prompt plus `tokens` does not fit
+///   in the context window of the model.
+/// * [`DavinciError::Transport`] if the request could not be sent.
+/// * [`DavinciError::Timeout`] if the server did not answer within [`DEFAULT_TIMEOUT`].
+/// * [`DavinciError::Api`] or [`DavinciError::Status`] if the server rejected the request.
+/// * [`DavinciError::Deserialize`] if the response could not be parsed.
+/// * [`DavinciError::EmptyChoices`] if the response did not contain any choice.
 ///
 pub async fn davinci(
     api_key: String,
     context: String,
     question: String,
     tokens: i32,
-) -> Result<String, Error> {
-    let bearer = String::from("Bearer ") + &api_key;
-
-    let resp: String = format!("{}.\nH: {}.\nIA:", context, question);
-    let prompt = Parameters {
-        model: String::from("text-davinci-003"),
-        prompt: resp,
-        temperature: 0.9,
-        max_tokens: tokens,
-        top_p: 1,
-        frequency_penalty: 0.0,
-        presence_penalty: 0.6,
-        stop: vec![String::from("\n")],
-    };
-
-    let client = Client::new();
-    let resp: Response = client
-        .post("https://api.openai.com/v1/completions")
-        .header("Content-Type", "application/json")
-        .header("Authorization", bearer)
-        .json(&prompt)
-        .send()
-        .await
-        .expect("Error while getting the response");
-
-    let openai_response: OpenAIResponse = resp
-        .json()
-        .await
-        .expect("Error while generating the response");
+) -> Result<String, DavinciError> {
+    let client = DavinciClient::new(api_key)?;
 
-    let formatted_response = format!("{}", openai_response.choices[0].text);
-    return Ok(formatted_response);
+    client.ask(&context, &question, tokens).await
 }
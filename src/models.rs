@@ -0,0 +1,130 @@
+//! The context windows of the models.
+//!
+//! A model reads the prompt and writes the answer in the same window of tokens,
+//! so the prompt tokens plus `max_tokens` can not be more than its context length.
+//! The [`ModelRegistry`] of a [`crate::DavinciClient`] knows the context length of the
+//! OpenAI models, and the client checks every request against it before sending it,
+//! as its [`ContextWindowPolicy`] says.
+//!
+//! ```
+//! use davinci::ModelRegistry;
+//!
+//! let mut models = ModelRegistry::default();
+//! models.insert("llama-3-8b-instruct", 8192);
+//!
+//! assert_eq!(models.context_length("text-davinci-003"), Some(4097));
+//! assert_eq!(models.context_length("gpt-4o-2024-08-06"), Some(128_000));
+//! assert_eq!(models.context_length("llama-3-8b-instruct"), Some(8192));
+//! assert_eq!(models.context_length("mistral-7b"), None);
+//! ```
+use std::collections::BTreeMap;
+
+/// The context lengths of the OpenAI models, in tokens.
+const KNOWN_MODELS: &[(&str, u32)] = &[
+    ("ada", 2049),
+    ("babbage", 2049),
+    ("curie", 2049),
+    ("davinci", 2049),
+    ("text-ada-001", 2049),
+    ("text-babbage-001", 2049),
+    ("text-curie-001", 2049),
+    ("text-davinci-001", 2049),
+    ("text-davinci-002", 4097),
+    ("text-davinci-003", 4097),
+    ("code-davinci-002", 8001),
+    ("babbage-002", 16384),
+    ("davinci-002", 16384),
+    ("gpt-3.5-turbo", 16385),
+    ("gpt-3.5-turbo-0301", 4096),
+    ("gpt-3.5-turbo-0613", 4096),
+    ("gpt-3.5-turbo-16k", 16385),
+    ("gpt-3.5-turbo-instruct", 4096),
+    ("gpt-4", 8192),
+    ("gpt-4-32k", 32768),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4-1106-preview", 128_000),
+    ("gpt-4-0125-preview", 128_000),
+    ("gpt-4-vision-preview", 128_000),
+    ("gpt-4o", 128_000),
+    ("gpt-4o-mini", 128_000),
+    ("gpt-4.1", 1_047_576),
+    ("gpt-4.1-mini", 1_047_576),
+    ("gpt-4.1-nano", 1_047_576),
+    ("o1", 200_000),
+    ("o1-mini", 128_000),
+    ("o1-preview", 128_000),
+    ("o3-mini", 200_000),
+];
+
+/// The context length of every model a client knows.
+///
+/// The default registry has the OpenAI models. A model is found by its exact name,
+/// or else by the longest known name it starts with followed by a `-`, so dated snapshots
+/// such as `gpt-4-0613` have the context length of `gpt-4`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelRegistry {
+    context_lengths: BTreeMap<String, u32>,
+}
+
+impl Default for ModelRegistry {
+    fn default() -> Self {
+        ModelRegistry {
+            context_lengths: KNOWN_MODELS
+                .iter()
+                .map(|(model, context_length)| (model.to_string(), *context_length))
+                .collect(),
+        }
+    }
+}
+
+impl ModelRegistry {
+    /// Returns a registry that does not know any model, so no request is checked.
+    pub fn empty() -> ModelRegistry {
+        ModelRegistry {
+            context_lengths: BTreeMap::new(),
+        }
+    }
+
+    /// Sets the context length of a model, such as one of a self-hosted server.
+    pub fn insert(&mut self, model: impl Into<String>, context_length: u32) {
+        self.context_lengths.insert(model.into(), context_length);
+    }
+
+    /// Returns the context length of a model, or `None` if the registry does not know it.
+    pub fn context_length(&self, model: &str) -> Option<u32> {
+        find_model(&self.context_lengths, model).copied()
+    }
+}
+
+/// Returns the value of `model` by its exact name, or else by the longest known name
+/// it starts with followed by a `-`.
+pub(crate) fn find_model<'a, V>(models: &'a BTreeMap<String, V>, model: &str) -> Option<&'a V> {
+    if let Some(value) = models.get(model) {
+        return Some(value);
+    }
+    models
+        .iter()
+        .filter(|(known, _)| {
+            model
+                .strip_prefix(known.as_str())
+                .is_some_and(|rest| rest.starts_with('-'))
+        })
+        .max_by_key(|(known, _)| known.len())
+        .map(|(_, value)| value)
+}
+
+/// What the client does with a request whose prompt tokens plus `max_tokens`
+/// do not fit in the context window of its model.
+///
+/// The requests for models the [`ModelRegistry`] does not know are always sent unchanged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ContextWindowPolicy {
+    /// Returns [`crate::DavinciError::ContextLengthExceeded`] without sending the request.
+    #[default]
+    Reject,
+    /// Lowers `max_tokens` to the tokens left after the prompt.
+    /// A prompt that fills the whole window is still rejected.
+    Clamp,
+    /// Sends the request unchanged and lets the server decide.
+    Ignore,
+}
@@ -0,0 +1,183 @@
+//! The prices of the models, to know what the requests cost.
+//!
+//! A [`PricingTable`] has the price of the prompt, cached prompt and completion tokens of every
+//! model it knows, in dollars per million tokens. The default table has the OpenAI prices;
+//! they change from time to time, so they can be overridden from a JSON file such as:
+//!
+//! ```json
+//! {
+//!     "gpt-4o": {"prompt": 2.5, "completion": 10.0, "cached_prompt": 1.25},
+//!     "my-fine-tuned-model": {"prompt": 0.3, "completion": 1.2}
+//! }
+//! ```
+//!
+//! The cost of a response is given by [`crate::CompletionResponse::cost`] or [`crate::Usage::cost`],
+//! and the cost of a request before it is sent by [`crate::DavinciClient::estimate_cost`].
+//!
+//! ```
+//! use davinci::{ModelPrice, PricingTable};
+//!
+//! let mut pricing = PricingTable::default();
+//! pricing.merge_json(r#"{"llama-3-8b-instruct": {"prompt": 0.05, "completion": 0.25}}"#).unwrap();
+//!
+//! assert_eq!(pricing.price("gpt-4o-2024-08-06"), pricing.price("gpt-4o"));
+//! assert_eq!(pricing.price("llama-3-8b-instruct"), Some(&ModelPrice::new(0.05, 0.25)));
+//! assert_eq!(pricing.price("mistral-7b"), None);
+//! ```
+use crate::models::find_model;
+use crate::{DavinciError, Usage};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// The OpenAI prices, in dollars per million prompt, completion and cached prompt tokens.
+const KNOWN_PRICES: &[(&str, f64, f64, Option<f64>)] = &[
+    ("ada", 0.4, 0.4, None),
+    ("babbage", 0.5, 0.5, None),
+    ("curie", 2.0, 2.0, None),
+    ("davinci", 20.0, 20.0, None),
+    ("text-ada-001", 0.4, 0.4, None),
+    ("text-babbage-001", 0.5, 0.5, None),
+    ("text-curie-001", 2.0, 2.0, None),
+    ("text-davinci-001", 20.0, 20.0, None),
+    ("text-davinci-002", 20.0, 20.0, None),
+    ("text-davinci-003", 20.0, 20.0, None),
+    ("babbage-002", 0.4, 0.4, None),
+    ("davinci-002", 2.0, 2.0, None),
+    ("gpt-3.5-turbo", 0.5, 1.5, None),
+    ("gpt-3.5-turbo-16k", 3.0, 4.0, None),
+    ("gpt-3.5-turbo-instruct", 1.5, 2.0, None),
+    ("gpt-4", 30.0, 60.0, None),
+    ("gpt-4-32k", 60.0, 120.0, None),
+    ("gpt-4-turbo", 10.0, 30.0, None),
+    ("gpt-4-1106-preview", 10.0, 30.0, None),
+    ("gpt-4-0125-preview", 10.0, 30.0, None),
+    ("gpt-4-vision-preview", 10.0, 30.0, None),
+    ("gpt-4o", 2.5, 10.0, Some(1.25)),
+    ("gpt-4o-mini", 0.15, 0.6, Some(0.075)),
+    ("gpt-4.1", 2.0, 8.0, Some(0.5)),
+    ("gpt-4.1-mini", 0.4, 1.6, Some(0.1)),
+    ("gpt-4.1-nano", 0.1, 0.4, Some(0.025)),
+    ("o1", 15.0, 60.0, Some(7.5)),
+    ("o1-mini", 1.1, 4.4, Some(0.55)),
+    ("o1-preview", 15.0, 60.0, Some(7.5)),
+    ("o3-mini", 1.1, 4.4, Some(0.55)),
+];
+
+/// The price of the tokens of a model, in dollars per million tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ModelPrice {
+    /// The price of the prompt tokens.
+    pub prompt: f64,
+    /// The price of the generated tokens.
+    pub completion: f64,
+    /// The price of the prompt tokens read from the prompt cache.
+    /// When it is `None`, they cost as much as the other prompt tokens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cached_prompt: Option<f64>,
+}
+
+impl ModelPrice {
+    /// Returns the price of a model without a discount for cached prompt tokens.
+    pub fn new(prompt: f64, completion: f64) -> ModelPrice {
+        ModelPrice {
+            prompt,
+            completion,
+            cached_prompt: None,
+        }
+    }
+
+    /// Sets the price of the prompt tokens read from the prompt cache.
+    pub fn cached_prompt(mut self, cached_prompt: f64) -> Self {
+        self.cached_prompt = Some(cached_prompt);
+        self
+    }
+
+    /// Returns the cost in dollars of `prompt_tokens` not read from the prompt cache,
+    /// `cached_tokens` read from it and `completion_tokens` generated.
+    pub fn cost(&self, prompt_tokens: u32, cached_tokens: u32, completion_tokens: u32) -> f64 {
+        let cached_prompt = self.cached_prompt.unwrap_or(self.prompt);
+        (f64::from(prompt_tokens) * self.prompt
+            + f64::from(cached_tokens) * cached_prompt
+            + f64::from(completion_tokens) * self.completion)
+            / 1_000_000.0
+    }
+}
+
+/// The price of every model a client knows.
+///
+/// A model is found by its exact name, or else by the longest known name it starts with
+/// followed by a `-`, as in [`crate::ModelRegistry`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PricingTable {
+    prices: BTreeMap<String, ModelPrice>,
+}
+
+impl Default for PricingTable {
+    fn default() -> Self {
+        PricingTable {
+            prices: KNOWN_PRICES
+                .iter()
+                .map(|(model, prompt, completion, cached_prompt)| {
+                    let price = ModelPrice {
+                        prompt: *prompt,
+                        completion: *completion,
+                        cached_prompt: *cached_prompt,
+                    };
+                    (model.to_string(), price)
+                })
+                .collect(),
+        }
+    }
+}
+
+impl PricingTable {
+    /// Returns a table that does not know any model.
+    pub fn empty() -> PricingTable {
+        PricingTable {
+            prices: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the default table, with the prices of the JSON file at `path` added or replaced.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DavinciError::Io`] if the file can not be read,
+    /// or [`DavinciError::Deserialize`] if it is not an object of prices by model.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<PricingTable, DavinciError> {
+        let path = path.as_ref();
+        let json = fs::read_to_string(path).map_err(|error| DavinciError::io(path, error))?;
+        let mut pricing = PricingTable::default();
+        pricing.merge_json(&json)?;
+        Ok(pricing)
+    }
+
+    /// Adds or replaces the prices of a JSON object of prices by model.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DavinciError::Deserialize`] if `json` is not an object of prices by model.
+    /// The table is not changed then.
+    pub fn merge_json(&mut self, json: &str) -> Result<(), DavinciError> {
+        let prices: BTreeMap<String, ModelPrice> = serde_json::from_str(json)?;
+        self.prices.extend(prices);
+        Ok(())
+    }
+
+    /// Sets the price of a model.
+    pub fn insert(&mut self, model: impl Into<String>, price: ModelPrice) {
+        self.prices.insert(model.into(), price);
+    }
+
+    /// Returns the price of a model, or `None` if the table does not know it.
+    pub fn price(&self, model: &str) -> Option<&ModelPrice> {
+        find_model(&self.prices, model)
+    }
+
+    /// Returns the cost in dollars of `usage` with `model`, or `None` if the table does not know it.
+    pub fn cost(&self, model: &str, usage: &Usage) -> Option<f64> {
+        Some(usage.cost(self.price(model)?))
+    }
+}
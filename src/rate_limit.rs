@@ -0,0 +1,375 @@
+//! A client-side limit of requests and tokens per minute.
+//!
+//! OpenAI limits every account to a number of requests per minute (RPM) and tokens
+//! per minute (TPM). A [`RateLimiter`] given to [`crate::DavinciClient`] keeps the requests
+//! under those limits instead of letting the server reject them: before sending a request,
+//! the client waits until the limiter has room for one request and for its estimated tokens,
+//! the prompt tokens plus `max_tokens` for every choice. Once the response arrives,
+//! the charge is corrected with the tokens of its [`crate::Usage`], or of the last chunk
+//! of a stream. The tokens are given back if the server rejects the request,
+//! the connection fails or the request is cancelled.
+//!
+//! A limiter is cheap to clone, and the clones share the same budget, so one limiter
+//! given to several clients keeps all of them under the quota together.
+//!
+//! ```no_run
+//! use davinci::{DavinciClient, RateLimiter};
+//!
+//! # fn run() -> Result<(), davinci::DavinciError> {
+//! let limiter = RateLimiter::builder()
+//!     .requests_per_minute(3_500)
+//!     .tokens_per_minute(90_000)
+//!     .build()?;
+//!
+//! let client = DavinciClient::builder()
+//!     .api_key("vj-JZkjskhdksKXOlncknjckukNKKnkJNKJNkNKNk")
+//!     .rate_limiter(limiter.clone())
+//!     .build()?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::sync::lock;
+use crate::{DavinciError, Usage};
+use futures::stream::{BoxStream, Stream, StreamExt};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A token bucket: it holds at most a minute of quota and refills continuously.
+///
+/// Its level goes below zero when requests reserve more than it holds,
+/// and they wait until it is back to zero.
+#[derive(Debug)]
+struct Bucket {
+    per_minute: f64,
+    level: f64,
+}
+
+impl Bucket {
+    fn new(per_minute: u32) -> Bucket {
+        Bucket {
+            per_minute: f64::from(per_minute),
+            level: f64::from(per_minute),
+        }
+    }
+
+    fn refill(&mut self, elapsed: Duration) {
+        self.level =
+            (self.level + elapsed.as_secs_f64() * self.per_minute / 60.0).min(self.per_minute);
+    }
+
+    /// Takes `amount` and returns how long to wait until the bucket is no longer in debt.
+    fn reserve(&mut self, amount: f64) -> Duration {
+        self.level -= amount;
+        if self.level >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.level * 60.0 / self.per_minute)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+    updated: Instant,
+}
+
+impl Buckets {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated);
+        self.updated = now;
+        for bucket in [&mut self.requests, &mut self.tokens].into_iter().flatten() {
+            bucket.refill(elapsed);
+        }
+    }
+}
+
+/// A limit of requests and tokens per minute, shared by every clone.
+///
+/// Build one with [`RateLimiter::builder`].
+/// The requests wait in the order they asked, as each one reserves its share right away.
+#[derive(Clone)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let buckets = self.lock();
+        f.debug_struct("RateLimiter")
+            .field(
+                "requests_per_minute",
+                &buckets.requests.as_ref().map(|bucket| bucket.per_minute),
+            )
+            .field(
+                "tokens_per_minute",
+                &buckets.tokens.as_ref().map(|bucket| bucket.per_minute),
+            )
+            .finish()
+    }
+}
+
+impl RateLimiter {
+    /// Returns a builder to configure a new limiter.
+    pub fn builder() -> RateLimiterBuilder {
+        RateLimiterBuilder::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Buckets> {
+        lock(&self.buckets)
+    }
+
+    /// Returns `true` if the limiter counts tokens, so the requests need an estimate of theirs.
+    pub fn limits_tokens(&self) -> bool {
+        self.lock().tokens.is_some()
+    }
+
+    /// Waits until there is room for one request and `tokens` tokens, and takes them.
+    ///
+    /// A request larger than a whole minute of tokens waits until the bucket has refilled
+    /// enough to pay for it, instead of waiting forever.
+    pub async fn acquire(&self, tokens: u32) {
+        self.reserve(tokens).await.keep();
+    }
+
+    /// Waits like [`RateLimiter::acquire`], and returns the reservation of the tokens,
+    /// given back if it is dropped, even while waiting, before [`TokenReservation::keep`].
+    pub(crate) async fn reserve(&self, tokens: u32) -> TokenReservation<'_> {
+        let wait = {
+            let mut buckets = self.lock();
+            buckets.refill();
+            let requests = buckets
+                .requests
+                .as_mut()
+                .map_or(Duration::ZERO, |bucket| bucket.reserve(1.0));
+            let tokens = buckets
+                .tokens
+                .as_mut()
+                .map_or(Duration::ZERO, |bucket| bucket.reserve(f64::from(tokens)));
+            requests.max(tokens)
+        };
+        let reservation = TokenReservation {
+            limiter: self,
+            tokens,
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        reservation
+    }
+
+    /// Corrects the charge of a stream that reserved `estimated` tokens with the usage
+    /// of its first chunk that has one. A stream that ends without any keeps the estimate.
+    pub(crate) fn settle_stream<S, T>(
+        &self,
+        stream: S,
+        estimated: u32,
+        usage: fn(&T) -> Option<&Usage>,
+    ) -> BoxStream<'static, Result<T, DavinciError>>
+    where
+        S: Stream<Item = Result<T, DavinciError>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let limiter = self.clone();
+        let mut estimated = Some(estimated);
+        stream
+            .inspect(move |item| {
+                if let Some(usage) = item.as_ref().ok().and_then(usage) {
+                    if let Some(estimated) = estimated.take() {
+                        limiter.settle(estimated, usage.total_tokens);
+                    }
+                }
+            })
+            .boxed()
+    }
+
+    /// Corrects the charge of a request that reserved `estimated` tokens and used `actual`:
+    /// the difference is given back, or taken if the estimate was too low.
+    pub fn settle(&self, estimated: u32, actual: u32) {
+        let mut buckets = self.lock();
+        buckets.refill();
+        if let Some(bucket) = buckets.tokens.as_mut() {
+            bucket.level =
+                (bucket.level + f64::from(estimated) - f64::from(actual)).min(bucket.per_minute);
+        }
+    }
+}
+
+/// The tokens reserved by a request, given back if it fails before the server processes it.
+pub(crate) struct TokenReservation<'a> {
+    limiter: &'a RateLimiter,
+    tokens: u32,
+}
+
+impl TokenReservation<'_> {
+    /// Keeps the tokens charged, as the server processed the request or may have.
+    pub(crate) fn keep(mut self) {
+        self.tokens = 0;
+    }
+}
+
+impl Drop for TokenReservation<'_> {
+    fn drop(&mut self) {
+        if self.tokens > 0 {
+            self.limiter.settle(self.tokens, 0);
+        }
+    }
+}
+
+/// A builder for [`RateLimiter`]. A limit that is not set is not enforced.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiterBuilder {
+    requests_per_minute: Option<u32>,
+    tokens_per_minute: Option<u32>,
+}
+
+impl RateLimiterBuilder {
+    /// Sets how many requests can be sent per minute.
+    pub fn requests_per_minute(mut self, requests_per_minute: u32) -> Self {
+        self.requests_per_minute = Some(requests_per_minute);
+        self
+    }
+
+    /// Sets how many tokens, of the prompts and of the answers, can be used per minute.
+    pub fn tokens_per_minute(mut self, tokens_per_minute: u32) -> Self {
+        self.tokens_per_minute = Some(tokens_per_minute);
+        self
+    }
+
+    /// Builds the limiter, with a full minute of quota available.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DavinciError::InvalidInput`] if a limit is zero.
+    pub fn build(self) -> Result<RateLimiter, DavinciError> {
+        for (name, limit) in [
+            ("requests_per_minute", self.requests_per_minute),
+            ("tokens_per_minute", self.tokens_per_minute),
+        ] {
+            if limit == Some(0) {
+                return Err(DavinciError::InvalidInput(format!(
+                    "{} must be positive",
+                    name
+                )));
+            }
+        }
+
+        Ok(RateLimiter {
+            buckets: Arc::new(Mutex::new(Buckets {
+                requests: self.requests_per_minute.map(Bucket::new),
+                tokens: self.tokens_per_minute.map(Bucket::new),
+                updated: Instant::now(),
+            })),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+
+    fn tokens_per_minute(tokens: u32) -> RateLimiter {
+        RateLimiter::builder()
+            .tokens_per_minute(tokens)
+            .build()
+            .unwrap()
+    }
+
+    fn token_level(limiter: &RateLimiter) -> f64 {
+        let mut buckets = limiter.lock();
+        buckets.refill();
+        buckets.tokens.as_ref().unwrap().level
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_refills_continuously_up_to_a_minute() {
+        let limiter = tokens_per_minute(600);
+        limiter.acquire(600).await;
+        assert_eq!(token_level(&limiter), 0.0);
+
+        tokio::time::advance(Duration::from_secs(6)).await;
+        assert!((token_level(&limiter) - 60.0).abs() < 1e-6);
+
+        tokio::time::advance(Duration::from_secs(600)).await;
+        assert_eq!(token_level(&limiter), 600.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn acquire_waits_until_there_is_room() {
+        let limiter = RateLimiter::builder()
+            .requests_per_minute(2)
+            .build()
+            .unwrap();
+        let start = Instant::now();
+        limiter.acquire(0).await;
+        limiter.acquire(0).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        limiter.acquire(0).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn request_larger_than_a_minute_waits_for_its_share() {
+        let limiter = tokens_per_minute(100);
+        let start = Instant::now();
+        limiter.acquire(150).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dropped_reservation_gives_the_tokens_back() {
+        let limiter = tokens_per_minute(100);
+        let reservation = limiter.reserve(80).await;
+        assert_eq!(token_level(&limiter), 20.0);
+        drop(reservation);
+        assert_eq!(token_level(&limiter), 100.0);
+
+        limiter.reserve(80).await.keep();
+        assert_eq!(token_level(&limiter), 20.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reservation_cancelled_while_waiting_gives_the_tokens_back() {
+        let limiter = tokens_per_minute(100);
+        limiter.acquire(100).await;
+
+        let waiting = tokio::time::timeout(Duration::from_secs(1), limiter.reserve(50)).await;
+        assert!(waiting.is_err());
+        // Only the refill of the second spent waiting is left.
+        assert!((token_level(&limiter) - 100.0 / 60.0).abs() < 1e-6);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn settle_gives_back_or_takes_the_difference() {
+        let limiter = tokens_per_minute(1_000);
+        limiter.acquire(500).await;
+
+        limiter.settle(500, 100);
+        assert_eq!(token_level(&limiter), 900.0);
+        limiter.settle(100, 300);
+        assert_eq!(token_level(&limiter), 700.0);
+        limiter.settle(1_000, 0);
+        assert_eq!(token_level(&limiter), 1_000.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn settle_stream_uses_the_first_usage() {
+        let limiter = tokens_per_minute(1_000);
+        limiter.acquire(500).await;
+        let usage = |total_tokens| Usage {
+            total_tokens,
+            ..Usage::default()
+        };
+        let chunks = vec![Ok(None), Ok(Some(usage(40))), Ok(Some(usage(70)))];
+
+        let stream = limiter.settle_stream(stream::iter(chunks), 500, Option::as_ref);
+        assert_eq!(stream.count().await, 3);
+        assert_eq!(token_level(&limiter), 960.0);
+    }
+}
@@ -0,0 +1,305 @@
+//! Retries of the requests that failed for a reason that may go away.
+//!
+//! A [`crate::DavinciClient`] sends a request again when the connection could not be made
+//! or was reset, or the server answered `429 Too Many Requests`, `500`, `502`, `503` or `504`.
+//! It waits longer after every failure, following its [`RetryPolicy`],
+//! unless the server says how long to wait in the `Retry-After`, `retry-after-ms`
+//! or `x-ratelimit-reset-*` headers.
+//!
+//! ```no_run
+//! use davinci::{DavinciClient, RetryPolicy};
+//! use std::time::Duration;
+//!
+//! # fn run() -> Result<(), davinci::DavinciError> {
+//! let client = DavinciClient::builder()
+//!     .api_key("vj-JZkjskhdksKXOlncknjckukNKKnkJNKJNkNKNk")
+//!     .retry_policy(
+//!         RetryPolicy::default()
+//!             .max_attempts(5)
+//!             .base_delay(Duration::from_secs(1))
+//!             .max_delay(Duration::from_secs(30)),
+//!     )
+//!     .on_retry(|retry| eprintln!("attempt {} failed, retrying in {:?}: {}", retry.attempt, retry.delay, retry.error))
+//!     .build()?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::DavinciError;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// How many times and how long apart a failed request is sent again.
+///
+/// The delay before the `n`th retry is `base_delay * 2^(n - 1)`, at most `max_delay`,
+/// reduced by a random part of up to `jitter` of it, so that clients that failed together
+/// do not retry together. A delay given by the server replaces it.
+///
+/// The default policy makes 3 attempts, waiting about 0.5 and 1 second,
+/// with a jitter of `0.25` and a maximum delay of 8 seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(8),
+            jitter: 0.25,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns a policy that never retries.
+    pub fn none() -> RetryPolicy {
+        RetryPolicy::default().max_attempts(1)
+    }
+
+    /// Sets how many times a request is sent at most, the first one included.
+    /// `0` is treated as `1`.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Sets the delay before the first retry, doubled after every failure.
+    pub fn base_delay(mut self, base_delay: Duration) -> Self {
+        self.base_delay = base_delay;
+        self
+    }
+
+    /// Sets the longest delay between two attempts, unless the server asks for a longer one.
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Sets the largest part of the delay, between `0.0` and `1.0`, that is randomly removed.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Returns how many times a request is sent at most, the first one included.
+    pub fn attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Returns `true` if a request that failed with `error` can be sent again.
+    ///
+    /// Only the failures that happen before the server handles the request, or that it
+    /// reports as temporary, are retried: connection errors, and the statuses
+    /// 429 (except for an exhausted quota), 500, 502, 503 and 504.
+    /// Timeouts and cancellations are not retried.
+    pub fn is_retryable(error: &DavinciError) -> bool {
+        match error {
+            DavinciError::Transport(error) => error.is_connect() || error.is_request(),
+            DavinciError::Api(error) if error.is_quota_exceeded() => false,
+            DavinciError::Api(_) | DavinciError::Status { .. } => matches!(
+                error.status(),
+                Some(
+                    StatusCode::TOO_MANY_REQUESTS
+                        | StatusCode::INTERNAL_SERVER_ERROR
+                        | StatusCode::BAD_GATEWAY
+                        | StatusCode::SERVICE_UNAVAILABLE
+                        | StatusCode::GATEWAY_TIMEOUT
+                )
+            ),
+            _ => false,
+        }
+    }
+
+    /// Returns the delay before the retry that follows the failed attempt `attempt`, counted from 1.
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = self
+            .base_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        delay.mul_f64(1.0 - self.jitter * random_fraction())
+    }
+}
+
+/// A failed attempt that is about to be retried, given to the hook of
+/// [`crate::DavinciClientBuilder::on_retry`].
+#[derive(Debug)]
+pub struct RetryAttempt<'a> {
+    /// The attempt that failed, counted from 1.
+    pub attempt: u32,
+    /// How many times the request is sent at most.
+    pub max_attempts: u32,
+    /// How long the client waits before the next attempt.
+    pub delay: Duration,
+    /// Why the attempt failed.
+    pub error: &'a DavinciError,
+}
+
+/// A function called before every retry.
+pub(crate) type RetryHook = Arc<dyn Fn(&RetryAttempt<'_>) + Send + Sync>;
+
+/// Returns how long the server asks to wait before the next request, if it says so.
+///
+/// `retry-after-ms` and `Retry-After` (in seconds or as an HTTP date) come first.
+/// Otherwise the longest of the `x-ratelimit-reset-requests` and `x-ratelimit-reset-tokens`
+/// durations, such as `1s` or `6m0s`, is used.
+pub(crate) fn server_delay(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    if let Some(millis) = header("retry-after-ms").and_then(|value| value.trim().parse().ok()) {
+        return seconds(f64::max(millis, 0.0) / 1000.0);
+    }
+    if let Some(value) = header("retry-after") {
+        let value = value.trim();
+        if let Ok(value) = value.parse::<f64>() {
+            return seconds(value);
+        }
+        if let Ok(date) = httpdate::parse_http_date(value) {
+            return Some(
+                date.duration_since(SystemTime::now())
+                    .unwrap_or(Duration::ZERO),
+            );
+        }
+    }
+
+    ["x-ratelimit-reset-requests", "x-ratelimit-reset-tokens"]
+        .into_iter()
+        .filter_map(|name| header(name).and_then(parse_reset))
+        .max()
+}
+
+/// Parses a duration such as `20ms`, `1.5s`, `6m0s` or `1h2m3s`. A bare number is in seconds.
+fn parse_reset(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(value) = value.parse::<f64>() {
+        return seconds(value);
+    }
+
+    let mut total = 0.0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let end = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .filter(|end| *end > 0)?;
+        let number: f64 = rest[..end].parse().ok()?;
+        rest = &rest[end..];
+
+        let (unit, seconds) = if rest.starts_with("ms") {
+            ("ms", 0.001)
+        } else if rest.starts_with('h') {
+            ("h", 3600.0)
+        } else if rest.starts_with('m') {
+            ("m", 60.0)
+        } else if rest.starts_with('s') {
+            ("s", 1.0)
+        } else {
+            return None;
+        };
+        total += number * seconds;
+        rest = &rest[unit.len()..];
+    }
+    seconds(total)
+}
+
+/// Returns a duration of `value` seconds, or `None` if it is infinite or too large.
+fn seconds(value: f64) -> Option<Duration> {
+    Duration::try_from_secs_f64(value.max(0.0)).ok()
+}
+
+/// Returns a random number between `0.0` and `1.0`, good enough to spread retries.
+fn random_fraction() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn parse_reset_reads_go_durations() {
+        assert_eq!(parse_reset("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(parse_reset("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_reset("20ms"), Some(Duration::from_millis(20)));
+        assert_eq!(parse_reset("1h2m3s"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_reset("2"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_reset("5x"), None);
+        assert_eq!(parse_reset("s"), None);
+    }
+
+    #[test]
+    fn server_delay_prefers_retry_after_ms() {
+        let headers = headers(&[("retry-after-ms", "250"), ("retry-after", "3")]);
+        assert_eq!(server_delay(&headers), Some(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn server_delay_reads_retry_after_in_seconds_or_as_a_date() {
+        let seconds = headers(&[("retry-after", "3")]);
+        assert_eq!(server_delay(&seconds), Some(Duration::from_secs(3)));
+
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(30));
+        let delay = server_delay(&headers(&[("retry-after", &date)])).unwrap();
+        assert!(delay > Duration::from_secs(28) && delay <= Duration::from_secs(30));
+
+        let past = httpdate::fmt_http_date(SystemTime::now() - Duration::from_secs(30));
+        assert_eq!(
+            server_delay(&headers(&[("retry-after", &past)])),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn server_delay_takes_the_longest_rate_limit_reset() {
+        let headers = headers(&[
+            ("x-ratelimit-reset-requests", "20ms"),
+            ("x-ratelimit-reset-tokens", "6m0s"),
+        ]);
+        assert_eq!(server_delay(&headers), Some(Duration::from_secs(360)));
+        assert_eq!(server_delay(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_maximum() {
+        let policy = RetryPolicy::default()
+            .base_delay(Duration::from_secs(1))
+            .max_delay(Duration::from_secs(5))
+            .jitter(0.0);
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        assert_eq!(policy.delay(3), Duration::from_secs(4));
+        assert_eq!(policy.delay(4), Duration::from_secs(5));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn delay_removes_at_most_the_jitter() {
+        let policy = RetryPolicy::default()
+            .base_delay(Duration::from_secs(4))
+            .max_delay(Duration::from_secs(4))
+            .jitter(0.25);
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay >= Duration::from_secs(3) && delay <= Duration::from_secs(4));
+        }
+    }
+}
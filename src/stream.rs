@@ -0,0 +1,425 @@
+//! Streaming of completions over server-sent events.
+//!
+//! When a request sets `stream: true`, the server sends the completion as it is generated,
+//! as a series of `data: {...}` events ended by `data: [DONE]`.
+//! [`crate::DavinciClient::stream_completion`] parses them into a [`CompletionStream`],
+//! a [`Stream`] of [`CompletionChunk`]s.
+//!
+//! ```no_run
+//! use davinci::{CompletionRequest, DavinciClient};
+//! use futures::StreamExt;
+//!
+//! # async fn run(client: DavinciClient) -> Result<(), davinci::DavinciError> {
+//! let request = CompletionRequest::new("Write a tagline for an ice cream shop.");
+//! let mut stream = client.stream_completion(&request).await?;
+//!
+//! while let Some(chunk) = stream.next().await {
+//!     print!("{}", chunk?.text().unwrap_or_default());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use crate::{ApiError, CancellationToken, Choice, CompletionResponse, DavinciError, Usage};
+use bytes::Bytes;
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use reqwest::{Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// The data of the event that ends a stream.
+const DONE: &str = "[DONE]";
+
+/// The options of a streamed request.
+///
+/// [`crate::DavinciClient`] sets `include_usage` on every stream, so that the budget
+/// and the rate limiter can be corrected with what the request really used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamOptions {
+    /// Whether the server sends the token usage in a last chunk, which has no choice.
+    pub include_usage: bool,
+}
+
+impl StreamOptions {
+    /// Sets `include_usage` in the options of a request, creating them if needed.
+    pub(crate) fn include_usage(options: &mut Option<StreamOptions>) {
+        options
+            .get_or_insert_with(StreamOptions::default)
+            .include_usage = true;
+    }
+}
+
+/// A part of a streamed completion.
+///
+/// Every chunk carries the text generated since the previous one, for one or more choices.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompletionChunk {
+    /// The unique identifier of the completion, the same for every chunk.
+    pub id: String,
+    /// The type of object, always `text_completion`.
+    pub object: String,
+    /// The Unix timestamp, in seconds, of when the completion was created.
+    pub created: u64,
+    /// The model that generates the completion.
+    pub model: String,
+    /// The new text of the choices. The last chunk of a choice has its finish reason.
+    pub choices: Vec<Choice>,
+    /// The token usage, sent in a last chunk without choices when the request
+    /// sets [`StreamOptions::include_usage`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+impl CompletionChunk {
+    /// Returns the new text of the first choice of the chunk.
+    pub fn text(&self) -> Option<&str> {
+        self.choices.first().map(|choice| choice.text.as_str())
+    }
+}
+
+/// A stream of [`CompletionChunk`]s, returned by [`crate::DavinciClient::stream_completion`].
+///
+/// It ends after the `[DONE]` event. An error event sent in the middle of the stream
+/// is returned as a [`DavinciError::Api`] item.
+pub struct CompletionStream {
+    inner: BoxStream<'static, Result<CompletionChunk, DavinciError>>,
+}
+
+impl fmt::Debug for CompletionStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompletionStream").finish_non_exhaustive()
+    }
+}
+
+impl CompletionStream {
+    /// Wraps any stream of chunks, such as one built in a test.
+    pub fn new<S>(stream: S) -> CompletionStream
+    where
+        S: Stream<Item = Result<CompletionChunk, DavinciError>> + Send + 'static,
+    {
+        CompletionStream {
+            inner: stream.boxed(),
+        }
+    }
+
+    /// Parses the server-sent events of a response, until `cancellation` is cancelled.
+    pub(crate) fn from_response(
+        response: Response,
+        cancellation: Option<CancellationToken>,
+    ) -> CompletionStream {
+        CompletionStream::new(until_cancelled(json_events(response), cancellation))
+    }
+
+    /// Reads the whole stream and joins the chunks into one response,
+    /// as if the request had not been streamed.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error of the stream, or [`DavinciError::EmptyChoices`]
+    /// if the stream ended without any choice.
+    pub async fn collect_response(mut self) -> Result<CompletionResponse, DavinciError> {
+        let mut response: Option<CompletionResponse> = None;
+        let mut choices: BTreeMap<u32, Choice> = BTreeMap::new();
+
+        while let Some(chunk) = self.next().await {
+            let chunk = chunk?;
+            let response = response.get_or_insert_with(|| CompletionResponse {
+                id: chunk.id.clone(),
+                object: chunk.object.clone(),
+                created: chunk.created,
+                model: chunk.model.clone(),
+                choices: Vec::new(),
+                usage: None,
+                system_fingerprint: None,
+            });
+            if chunk.usage.is_some() {
+                response.usage = chunk.usage;
+            }
+
+            for part in chunk.choices {
+                match choices.get_mut(&part.index) {
+                    Some(choice) => {
+                        choice.text.push_str(&part.text);
+                        match (&mut choice.logprobs, part.logprobs) {
+                            (Some(logprobs), Some(part)) => logprobs.extend(part),
+                            (logprobs @ None, part) => *logprobs = part,
+                            (Some(_), None) => {}
+                        }
+                        if part.finish_reason.is_some() {
+                            choice.finish_reason = part.finish_reason;
+                        }
+                    }
+                    None => {
+                        choices.insert(part.index, part);
+                    }
+                }
+            }
+        }
+
+        match response {
+            Some(mut response) if !choices.is_empty() => {
+                response.choices = choices.into_values().collect();
+                Ok(response)
+            }
+            _ => Err(DavinciError::EmptyChoices),
+        }
+    }
+
+    /// Reads the whole stream and returns the text of the first choice.
+    ///
+    /// # Errors
+    ///
+    /// See [`CompletionStream::collect_response`].
+    pub async fn collect_text(self) -> Result<String, DavinciError> {
+        self.collect_response().await?.into_text()
+    }
+}
+
+impl Stream for CompletionStream {
+    type Item = Result<CompletionChunk, DavinciError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+/// Ends a stream with a [`DavinciError::Cancelled`] item as soon as `cancellation` is cancelled.
+///
+/// Dropping the inner stream closes the connection.
+pub(crate) fn until_cancelled<S, T>(
+    stream: S,
+    cancellation: Option<CancellationToken>,
+) -> BoxStream<'static, Result<T, DavinciError>>
+where
+    S: Stream<Item = Result<T, DavinciError>> + Send + 'static,
+    T: Send + 'static,
+{
+    let cancellation = match cancellation {
+        Some(cancellation) => cancellation,
+        None => return stream.boxed(),
+    };
+
+    stream::unfold(Some(stream.boxed()), move |inner| {
+        let cancellation = cancellation.clone();
+        async move {
+            let mut inner = inner?;
+            tokio::select! {
+                biased;
+                _ = cancellation.cancelled() => Some((Err(DavinciError::Cancelled), None)),
+                item = inner.next() => item.map(|item| (item, Some(inner))),
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Parses the data of a server-sent event as a `T`, or as an error object.
+///
+/// An error sent in the middle of a stream has the status of the response, which is a success.
+fn parse_event<T: DeserializeOwned>(status: StatusCode, data: &str) -> Result<T, DavinciError> {
+    if let Some(error) = ApiError::from_body(status, data) {
+        return Err(DavinciError::Api(error));
+    }
+    Ok(serde_json::from_str(data)?)
+}
+
+/// Splits the body of a response into server-sent events and parses their data as JSON.
+///
+/// The stream ends at the `[DONE]` event, at the end of the body or after the first error.
+pub(crate) fn json_events<T>(response: Response) -> impl Stream<Item = Result<T, DavinciError>>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    let status = response.status();
+    let state = EventReader {
+        body: response.bytes_stream().boxed(),
+        buffer: Vec::new(),
+        finished: false,
+    };
+
+    stream::unfold(state, move |mut state| async move {
+        if state.finished {
+            return None;
+        }
+        match state.next_data().await {
+            Ok(Some(data)) if data == DONE => None,
+            Ok(Some(data)) => {
+                let item = parse_event(status, &data);
+                state.finished = item.is_err();
+                Some((item, state))
+            }
+            Ok(None) => None,
+            Err(error) => {
+                state.finished = true;
+                Some((Err(error), state))
+            }
+        }
+    })
+}
+
+struct EventReader {
+    body: BoxStream<'static, reqwest::Result<Bytes>>,
+    buffer: Vec<u8>,
+    finished: bool,
+}
+
+impl EventReader {
+    /// Returns the data of the next event that has some, or `None` at the end of the body.
+    async fn next_data(&mut self) -> Result<Option<String>, DavinciError> {
+        loop {
+            while let Some(event) = self.take_event() {
+                if let Some(data) = event_data(&event) {
+                    return Ok(Some(data));
+                }
+            }
+
+            match self.body.next().await {
+                // The carriage returns are dropped so that `\r\n` line endings become `\n`.
+                // They can not be part of a multi-byte character, so a character split
+                // between two reads is kept whole in the buffer.
+                Some(bytes) => self
+                    .buffer
+                    .extend(bytes?.iter().filter(|byte| **byte != b'\r')),
+                None => {
+                    let rest = std::mem::take(&mut self.buffer);
+                    return Ok(event_data(&String::from_utf8_lossy(&rest)));
+                }
+            }
+        }
+    }
+
+    /// Removes the first complete event from the buffer.
+    fn take_event(&mut self) -> Option<String> {
+        let end = self.buffer.windows(2).position(|pair| pair == b"\n\n")?;
+        let event = String::from_utf8_lossy(&self.buffer[..end]).into_owned();
+        self.buffer.drain(..end + 2);
+        Some(event)
+    }
+}
+
+/// Returns the joined `data:` lines of an event, or `None` if it does not have any.
+fn event_data(event: &str) -> Option<String> {
+    let lines: Vec<&str> = event
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|data| data.strip_prefix(' ').unwrap_or(data))
+        .collect();
+
+    if lines.is_empty() {
+        None
+    } else {
+        Some(lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn response(chunks: Vec<Result<&'static [u8], std::io::Error>>) -> Response {
+        let body = reqwest::Body::wrap_stream(stream::iter(chunks));
+        Response::from(http::Response::builder().status(200).body(body).unwrap())
+    }
+
+    async fn events(
+        chunks: Vec<Result<&'static [u8], std::io::Error>>,
+    ) -> Vec<Result<Value, DavinciError>> {
+        json_events(response(chunks)).collect().await
+    }
+
+    #[test]
+    fn event_data_joins_the_data_lines() {
+        assert_eq!(event_data("data: {}"), Some("{}".to_string()));
+        assert_eq!(event_data("data:{}"), Some("{}".to_string()));
+        assert_eq!(
+            event_data("event: message\ndata: first\ndata:  second\nid: 1"),
+            Some("first\n second".to_string())
+        );
+        assert_eq!(event_data(": keep-alive"), None);
+        assert_eq!(event_data(""), None);
+    }
+
+    #[test]
+    fn take_event_removes_complete_events_only() {
+        let mut reader = EventReader {
+            body: stream::empty().boxed(),
+            buffer: b"data: 1\n\ndata: 2\n\ndata: 3".to_vec(),
+            finished: false,
+        };
+        assert_eq!(reader.take_event(), Some("data: 1".to_string()));
+        assert_eq!(reader.take_event(), Some("data: 2".to_string()));
+        assert_eq!(reader.take_event(), None);
+        assert_eq!(reader.buffer, b"data: 3");
+    }
+
+    #[tokio::test]
+    async fn json_events_stop_at_done() {
+        let items = events(vec![Ok(
+            b"data: {\"n\":1}\n\ndata: {\"n\":2}\n\ndata: [DONE]\n\ndata: {\"n\":3}\n\n",
+        )])
+        .await;
+        let items: Vec<Value> = items.into_iter().map(Result::unwrap).collect();
+        assert_eq!(items, vec![json!({"n": 1}), json!({"n": 2})]);
+    }
+
+    #[tokio::test]
+    async fn json_events_accept_crlf_and_events_split_across_reads() {
+        let items = events(vec![
+            Ok(b"data: {\"n\":"),
+            Ok(b"1}\r\n\r"),
+            Ok(b"\n: comment\r\n\r\ndata: {\"n\":2}"),
+        ])
+        .await;
+        let items: Vec<Value> = items.into_iter().map(Result::unwrap).collect();
+        assert_eq!(items, vec![json!({"n": 1}), json!({"n": 2})]);
+    }
+
+    #[tokio::test]
+    async fn json_events_keep_a_character_split_across_reads() {
+        let text = "data: {\"text\":\"caf\u{e9} \u{1f366}\"}\n\n".as_bytes();
+        let (first, rest) = text.split_at(text.len() - 8);
+        let (second, third) = rest.split_at(2);
+        let items = events(vec![Ok(first), Ok(second), Ok(third)]).await;
+        assert_eq!(items.len(), 1);
+        assert_eq!(
+            items[0].as_ref().unwrap(),
+            &json!({"text": "caf\u{e9} \u{1f366}"})
+        );
+    }
+
+    #[tokio::test]
+    async fn json_events_end_after_an_error_event() {
+        let items = events(vec![Ok(concat!(
+            "data: {\"n\":1}\n\n",
+            "data: {\"error\":{\"message\":\"overloaded\",\"type\":\"server_error\"}}\n\n",
+            "data: {\"n\":2}\n\n",
+        )
+        .as_bytes())])
+        .await;
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].as_ref().unwrap(), &json!({"n": 1}));
+        assert!(
+            matches!(&items[1], Err(DavinciError::Api(error)) if error.message == "overloaded")
+        );
+    }
+
+    #[tokio::test]
+    async fn json_events_end_after_a_broken_body() {
+        let items = events(vec![
+            Ok(b"data: {\"n\":1}\n\n"),
+            Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "reset",
+            )),
+            Ok(b"data: {\"n\":2}\n\n"),
+        ])
+        .await;
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].as_ref().unwrap(), &json!({"n": 1}));
+        assert!(items[1].is_err());
+    }
+}
@@ -0,0 +1,12 @@
+//! Helpers for the state shared by the clones of a type and by the tasks that use it.
+use std::sync::{Mutex, MutexGuard};
+
+/// Locks `mutex`, even if a thread panicked while holding it.
+///
+/// The shared state of the crate is only changed in steps that leave it consistent,
+/// such as one push or one update of a counter, so a panic while holding a lock is harmless.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
@@ -0,0 +1,327 @@
+//! Templates that turn a context and a transcript into a completion prompt.
+//!
+//! A [`PromptTemplate`] has a header with named variables, such as `{context}`,
+//! followed by the turns of the conversation, each one written as a speaker label
+//! and a text. The labels, the separators and the punctuation are configurable.
+//!
+//! ```
+//! use davinci::{Conversation, PromptTemplate};
+//!
+//! let template = PromptTemplate::builder()
+//!     .header("{context}\nThe assistant speaks {language}.")
+//!     .variable("language", "French")
+//!     .labels("User", "Bot")
+//!     .build()
+//!     .unwrap();
+//! let conversation = Conversation::with_template("You are a helpful assistant", template);
+//!
+//! assert_eq!(
+//!     conversation.prompt("Where is Paris?"),
+//!     "You are a helpful assistant\nThe assistant speaks French.\nUser: Where is Paris?\nBot:"
+//! );
+//! ```
+use crate::{DavinciError, Turn};
+use std::collections::BTreeMap;
+
+/// The name of the variable filled with the context of the conversation.
+pub const CONTEXT_VARIABLE: &str = "context";
+
+/// A template that renders a context and the turns of a conversation into a prompt.
+///
+/// The prompt is the header, then for every turn the human label and the question,
+/// and the AI label and the answer, ending with the AI label so that the model writes the answer:
+///
+/// ```text
+/// {header}{header_separator}{human}{label_separator}{question}{turn_separator}{ai}{label_separator}{answer}
+/// {turn_separator}{human}{label_separator}{question}{turn_separator}{ai}:
+/// ```
+///
+/// Build a custom one with [`PromptTemplate::builder`], or use a preset:
+/// [`PromptTemplate::chat_transcript`] (the default), [`PromptTemplate::question_answer`]
+/// or [`PromptTemplate::instruction`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PromptTemplate {
+    header: String,
+    variables: BTreeMap<String, String>,
+    human_label: String,
+    ai_label: String,
+    label_separator: String,
+    header_separator: String,
+    turn_separator: String,
+    sentence_end: String,
+    stop: Vec<String>,
+}
+
+impl Default for PromptTemplate {
+    fn default() -> Self {
+        PromptTemplate::chat_transcript()
+    }
+}
+
+impl PromptTemplate {
+    /// Returns a builder to configure a new template.
+    /// It starts from the settings of [`PromptTemplate::chat_transcript`].
+    pub fn builder() -> PromptTemplateBuilder {
+        PromptTemplateBuilder::default()
+    }
+
+    /// A transcript between `Human:` and `AI:`, one line per message,
+    /// like the example context of [`crate::davinci`].
+    pub fn chat_transcript() -> PromptTemplate {
+        PromptTemplateBuilder::default().template
+    }
+
+    /// Questions and answers, as `Q:` and `A:` lines after the context.
+    pub fn question_answer() -> PromptTemplate {
+        PromptTemplate::builder()
+            .labels("Q", "A")
+            .header_separator("\n\n")
+            .build()
+            .expect("the question answer preset is valid")
+    }
+
+    /// An instruction and its response, for instruction-tuned models.
+    pub fn instruction() -> PromptTemplate {
+        PromptTemplate::builder()
+            .labels("### Instruction", "### Response")
+            .label_separator(":\n")
+            .header_separator("\n\n")
+            .turn_separator("\n\n")
+            .build()
+            .expect("the instruction preset is valid")
+    }
+
+    /// Returns the label written before the questions.
+    pub fn human_label(&self) -> &str {
+        &self.human_label
+    }
+
+    /// Returns the label written before the answers.
+    pub fn ai_label(&self) -> &str {
+        &self.ai_label
+    }
+
+    /// Returns the sequences where the model must stop, so that it does not
+    /// go on writing the next question itself.
+    pub fn stop(&self) -> &[String] {
+        &self.stop
+    }
+
+    /// Renders the prompt that asks `question` after `turns`.
+    pub fn render(&self, context: &str, turns: &[Turn], question: &str) -> String {
+        let mut prompt = self.render_header(context);
+        let mut separator = if prompt.is_empty() {
+            ""
+        } else {
+            self.header_separator.as_str()
+        };
+
+        for turn in turns {
+            prompt.push_str(separator);
+            self.push_message(
+                &mut prompt,
+                &self.human_label,
+                &self.sentence(&turn.question),
+            );
+            prompt.push_str(&self.turn_separator);
+            self.push_message(&mut prompt, &self.ai_label, &turn.answer);
+            separator = &self.turn_separator;
+        }
+
+        prompt.push_str(separator);
+        self.push_message(&mut prompt, &self.human_label, &self.sentence(question));
+        prompt.push_str(&self.turn_separator);
+        prompt.push_str(&self.ai_label);
+        prompt.push_str(self.label_separator.trim_end());
+        prompt
+    }
+
+    fn push_message(&self, prompt: &mut String, label: &str, text: &str) {
+        prompt.push_str(label);
+        prompt.push_str(&self.label_separator);
+        prompt.push_str(text);
+    }
+
+    /// Fills the variables of the header.
+    fn render_header(&self, context: &str) -> String {
+        let mut header = String::new();
+        for part in parse(&self.header).expect("the header was checked by the builder") {
+            match part {
+                Part::Text(text) => header.push_str(text),
+                Part::Variable(CONTEXT_VARIABLE) => header.push_str(&self.sentence(context)),
+                Part::Variable(name) => header.push_str(&self.variables[name]),
+            }
+        }
+        header
+    }
+
+    /// Adds the sentence end to a text, unless it is empty or already ends with punctuation.
+    fn sentence(&self, text: &str) -> String {
+        let text = text.trim_end();
+        match text.chars().last() {
+            Some(last) if !last.is_ascii_punctuation() => format!("{}{}", text, self.sentence_end),
+            _ => text.to_string(),
+        }
+    }
+}
+
+/// A builder for [`PromptTemplate`].
+#[derive(Debug, Clone)]
+pub struct PromptTemplateBuilder {
+    template: PromptTemplate,
+    stop: Option<Vec<String>>,
+}
+
+impl Default for PromptTemplateBuilder {
+    fn default() -> Self {
+        PromptTemplateBuilder {
+            template: PromptTemplate {
+                header: format!("{{{}}}", CONTEXT_VARIABLE),
+                variables: BTreeMap::new(),
+                human_label: String::from("Human"),
+                ai_label: String::from("AI"),
+                label_separator: String::from(": "),
+                header_separator: String::from("\n"),
+                turn_separator: String::from("\n"),
+                sentence_end: String::new(),
+                stop: vec![String::from("\nHuman:")],
+            },
+            stop: None,
+        }
+    }
+}
+
+impl PromptTemplateBuilder {
+    /// Sets the header, written before the turns. Default: `{context}`.
+    ///
+    /// `{name}` is replaced by the value of the variable `name`,
+    /// `{context}` by the context of the conversation, and `{{` and `}}` by braces.
+    pub fn header(mut self, header: impl Into<String>) -> Self {
+        self.template.header = header.into();
+        self
+    }
+
+    /// Sets the value of a variable of the header.
+    pub fn variable(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.template.variables.insert(name.into(), value.into());
+        self
+    }
+
+    /// Sets the labels of the questions and of the answers. Default: `Human` and `AI`.
+    pub fn labels(mut self, human: impl Into<String>, ai: impl Into<String>) -> Self {
+        self.template.human_label = human.into();
+        self.template.ai_label = ai.into();
+        self
+    }
+
+    /// Sets what is written between a label and its text. Default: `": "`.
+    pub fn label_separator(mut self, separator: impl Into<String>) -> Self {
+        self.template.label_separator = separator.into();
+        self
+    }
+
+    /// Sets what is written between the header and the first turn. Default: `"\n"`.
+    pub fn header_separator(mut self, separator: impl Into<String>) -> Self {
+        self.template.header_separator = separator.into();
+        self
+    }
+
+    /// Sets what is written between two messages. Default: `"\n"`.
+    pub fn turn_separator(mut self, separator: impl Into<String>) -> Self {
+        self.template.turn_separator = separator.into();
+        self
+    }
+
+    /// Sets the suffix added to the context and to the questions that do not already end
+    /// with punctuation, such as `"."`. Default: none.
+    ///
+    /// ```
+    /// use davinci::{Conversation, PromptTemplate};
+    ///
+    /// let template = PromptTemplate::builder().sentence_end(".").build().unwrap();
+    /// let conversation = Conversation::with_template("You are a tutor", template);
+    ///
+    /// assert_eq!(conversation.prompt("Why is the sky blue?"), "You are a tutor.\nHuman: Why is the sky blue?\nAI:");
+    /// assert_eq!(conversation.prompt("Tell me a joke"), "You are a tutor.\nHuman: Tell me a joke.\nAI:");
+    /// ```
+    pub fn sentence_end(mut self, sentence_end: impl Into<String>) -> Self {
+        self.template.sentence_end = sentence_end.into();
+        self
+    }
+
+    /// Sets the sequences where the model must stop.
+    /// Default: the turn separator followed by the human label, so the model stops
+    /// when it starts writing the next question.
+    pub fn stop<I, S>(mut self, stop: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.stop = Some(stop.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Builds the template.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DavinciError::InvalidInput`] if the header has an unclosed brace
+    /// or a variable without a value.
+    pub fn build(self) -> Result<PromptTemplate, DavinciError> {
+        let mut template = self.template;
+
+        for part in parse(&template.header)? {
+            if let Part::Variable(name) = part {
+                if name != CONTEXT_VARIABLE && !template.variables.contains_key(name) {
+                    return Err(DavinciError::InvalidInput(format!(
+                        "the variable {{{}}} of the prompt template has no value",
+                        name
+                    )));
+                }
+            }
+        }
+
+        template.stop = match self.stop {
+            Some(stop) => stop,
+            None => vec![format!(
+                "{}{}{}",
+                template.turn_separator,
+                template.human_label,
+                template.label_separator.trim_end()
+            )],
+        };
+        Ok(template)
+    }
+}
+
+enum Part<'a> {
+    Text(&'a str),
+    Variable(&'a str),
+}
+
+/// Splits a header into texts and `{variables}`.
+fn parse(header: &str) -> Result<Vec<Part<'_>>, DavinciError> {
+    let mut parts = Vec::new();
+    let mut rest = header;
+
+    while let Some(start) = rest.find(['{', '}']) {
+        parts.push(Part::Text(&rest[..start]));
+        let tail = &rest[start..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            parts.push(Part::Text(&tail[..1]));
+            rest = &tail[2..];
+        } else if tail.starts_with('{') {
+            let end = tail.find('}').ok_or_else(|| {
+                DavinciError::InvalidInput(String::from("the prompt template has an unclosed {"))
+            })?;
+            parts.push(Part::Variable(tail[1..end].trim()));
+            rest = &tail[end + 1..];
+        } else {
+            return Err(DavinciError::InvalidInput(String::from(
+                "the prompt template has an unmatched }, write }} for a brace",
+            )));
+        }
+    }
+    parts.push(Part::Text(rest));
+    Ok(parts)
+}
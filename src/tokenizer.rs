@@ -0,0 +1,170 @@
+//! Offline tokenizer, to count tokens without calling the API.
+//!
+//! The GPT family of models process text using tokens, which are common sequences of characters.
+//! The byte pair encodings of the OpenAI models are embedded in the crate,
+//! so the exact number of tokens of a prompt is known before sending it:
+//!
+//! * [`Encoding::R50kBase`] - GPT-3 models such as `davinci`.
+//! * [`Encoding::P50kBase`] - `text-davinci-002`, `text-davinci-003` and the Codex models.
+//! * [`Encoding::Cl100kBase`] - `gpt-3.5-turbo`, `gpt-4` and the `text-embedding-3` models.
+//! * [`Encoding::O200kBase`] - `gpt-4o` and the `o1` models.
+//!
+//! ```
+//! use davinci::tokenizer::{count_tokens, decode, encode};
+//!
+//! assert_eq!(count_tokens("text-davinci-003", "Hello, who are you?"), 6);
+//!
+//! let tokens = encode("gpt-4", "Hello, who are you?");
+//! assert_eq!(decode("gpt-4", &tokens).unwrap(), "Hello, who are you?");
+//! ```
+//!
+//! Each encoding is loaded the first time it is used, which takes a fraction of a second.
+use crate::{ChatMessage, DavinciError};
+use std::sync::OnceLock;
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+use tiktoken_rs::CoreBPE;
+
+/// The tokens added by the chat format around every message, including its role,
+/// which is always a single token.
+const TOKENS_PER_MESSAGE: usize = 4;
+
+/// The tokens that start the answer of the assistant, added once per chat request.
+const TOKENS_PER_REPLY: usize = 3;
+
+/// A byte pair encoding used by OpenAI models.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    /// The encoding of the GPT-3 models, also known as `gpt2`.
+    R50kBase,
+    /// The encoding of `text-davinci-002`, `text-davinci-003` and the Codex models.
+    P50kBase,
+    /// The encoding of `gpt-3.5-turbo`, `gpt-4` and the recent embedding models.
+    Cl100kBase,
+    /// The encoding of `gpt-4o` and the `o1` models.
+    O200kBase,
+}
+
+impl Encoding {
+    /// Returns the encoding of a model, or `None` if the model is not an OpenAI model known
+    /// by the crate.
+    pub fn for_model(model: &str) -> Option<Encoding> {
+        match get_tokenizer(model)? {
+            Tokenizer::R50kBase | Tokenizer::Gpt2 => Some(Encoding::R50kBase),
+            Tokenizer::P50kBase | Tokenizer::P50kEdit => Some(Encoding::P50kBase),
+            Tokenizer::Cl100kBase => Some(Encoding::Cl100kBase),
+            Tokenizer::O200kBase => Some(Encoding::O200kBase),
+        }
+    }
+
+    /// Returns the encoding of a model, or [`Encoding::Cl100kBase`] for the models
+    /// the crate does not know, such as the ones of self-hosted servers.
+    /// The counts of those models are an approximation.
+    pub fn for_model_or_default(model: &str) -> Encoding {
+        Encoding::for_model(model).unwrap_or(Encoding::Cl100kBase)
+    }
+
+    /// Returns the name of the encoding, such as `cl100k_base`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::R50kBase => "r50k_base",
+            Encoding::P50kBase => "p50k_base",
+            Encoding::Cl100kBase => "cl100k_base",
+            Encoding::O200kBase => "o200k_base",
+        }
+    }
+
+    fn bpe(&self) -> &'static CoreBPE {
+        static R50K_BASE: OnceLock<CoreBPE> = OnceLock::new();
+        static P50K_BASE: OnceLock<CoreBPE> = OnceLock::new();
+        static CL100K_BASE: OnceLock<CoreBPE> = OnceLock::new();
+        static O200K_BASE: OnceLock<CoreBPE> = OnceLock::new();
+
+        // The encodings are embedded in the crate, so loading them can not fail.
+        match self {
+            Encoding::R50kBase => {
+                R50K_BASE.get_or_init(|| tiktoken_rs::r50k_base().expect("r50k_base is embedded"))
+            }
+            Encoding::P50kBase => {
+                P50K_BASE.get_or_init(|| tiktoken_rs::p50k_base().expect("p50k_base is embedded"))
+            }
+            Encoding::Cl100kBase => CL100K_BASE
+                .get_or_init(|| tiktoken_rs::cl100k_base().expect("cl100k_base is embedded")),
+            Encoding::O200kBase => O200K_BASE
+                .get_or_init(|| tiktoken_rs::o200k_base().expect("o200k_base is embedded")),
+        }
+    }
+
+    /// Splits a text into tokens.
+    ///
+    /// Special tokens such as `<|endoftext|>` are encoded as plain text.
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        self.bpe().encode_ordinary(text)
+    }
+
+    /// Joins tokens back into a text.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`DavinciError::InvalidInput`] if a token does not exist in the encoding,
+    /// or the tokens do not form valid UTF-8.
+    pub fn decode(&self, tokens: &[u32]) -> Result<String, DavinciError> {
+        self.bpe().decode(tokens.to_vec()).map_err(|error| {
+            DavinciError::InvalidInput(format!(
+                "the tokens can not be decoded with {}: {}",
+                self.name(),
+                error
+            ))
+        })
+    }
+
+    /// Returns the number of tokens of a text.
+    pub fn count(&self, text: &str) -> usize {
+        self.encode(text).len()
+    }
+
+    /// Returns the number of prompt tokens of chat messages, including the tokens
+    /// the chat format adds around every message and before the answer.
+    pub fn count_messages(&self, messages: &[ChatMessage]) -> usize {
+        messages
+            .iter()
+            .map(|message| {
+                let name = message
+                    .name
+                    .as_deref()
+                    .map_or(0, |name| self.count(name) + 1);
+                TOKENS_PER_MESSAGE + self.count(message.text()) + name
+            })
+            .sum::<usize>()
+            + TOKENS_PER_REPLY
+    }
+}
+
+/// Returns the number of tokens of a text for a model.
+///
+/// The models the crate does not know are counted with `cl100k_base`.
+pub fn count_tokens(model: &str, text: &str) -> usize {
+    Encoding::for_model_or_default(model).count(text)
+}
+
+/// Returns the number of prompt tokens of chat messages for a model.
+///
+/// The models the crate does not know are counted with `cl100k_base`.
+pub fn count_message_tokens(model: &str, messages: &[ChatMessage]) -> usize {
+    Encoding::for_model_or_default(model).count_messages(messages)
+}
+
+/// Splits a text into the tokens of a model.
+///
+/// The models the crate does not know use `cl100k_base`.
+pub fn encode(model: &str, text: &str) -> Vec<u32> {
+    Encoding::for_model_or_default(model).encode(text)
+}
+
+/// Joins the tokens of a model back into a text.
+///
+/// # Errors
+///
+/// See [`Encoding::decode`].
+pub fn decode(model: &str, tokens: &[u32]) -> Result<String, DavinciError> {
+    Encoding::for_model_or_default(model).decode(tokens)
+}